@@ -1,16 +1,37 @@
-use std::time::Instant;
-use std::{path::PathBuf, process::Stdio, str::from_utf8};
+use glob::{Pattern, PatternError};
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output, Stdio},
+    str::from_utf8,
+};
 use tokio::{
     fs::File,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Stdout},
-    process::Command,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Stdout},
+    process::{Child, Command},
 };
+use walkdir::WalkDir;
 
-pub mod maintain;
-pub mod sync;
+use crate::config::RepoConfig;
 
-#[cfg(not(target_os = "linux"))]
 pub mod allocate;
+pub mod coverage;
+pub mod drill;
+pub mod guard;
+pub mod maintain;
+pub mod preferred;
+pub mod status;
+pub mod unchanged;
+pub mod sync;
+pub mod unused;
+pub mod watch;
 
 pub enum LogTarget<'a> {
     File(&'a mut File),
@@ -30,11 +51,412 @@ pub async fn log(message: &str, target: &mut LogTarget<'_>) {
     }
 }
 
+/// Git directory of a repository, also when `.git` is a file pointing elsewhere as in worktrees
+/// and submodules, asked from git once per repository
+fn git_dir_path(repo_path: &Path) -> PathBuf {
+    lazy_static! {
+        static ref GIT_DIR_PATHS: Mutex<HashMap<PathBuf, PathBuf>> = Mutex::new(HashMap::new());
+    }
+
+    if let Some(git_dir_path) = GIT_DIR_PATHS.lock().unwrap().get(repo_path) {
+        return git_dir_path.clone();
+    }
+    let Some(git_dir_path) = std::process::Command::new("git")
+        .args(["rev-parse", "--absolute-git-dir"])
+        .current_dir(repo_path)
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| {
+            Some(PathBuf::from(
+                from_utf8(&x.stdout).ok()?.trim_end_matches('\n'),
+            ))
+        })
+    else {
+        // not kept, the repository may not be set up yet
+        return repo_path.join(".git");
+    };
+    GIT_DIR_PATHS
+        .lock()
+        .unwrap()
+        .insert(repo_path.to_path_buf(), git_dir_path.clone());
+    git_dir_path
+}
+
+pub fn repo_state_path(repo_path: &Path, name: &str) -> PathBuf {
+    git_dir_path(repo_path).join("annex/archiver").join(name)
+}
+
+pub fn read_repo_state<T: DeserializeOwned + Default>(repo_path: &Path, name: &str) -> T {
+    fs::read_to_string(repo_state_path(repo_path, name))
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+pub fn write_repo_state<T: Serialize>(
+    repo_path: &Path,
+    name: &str,
+    state: &T,
+) -> Result<(), std::io::Error> {
+    let state_path = repo_state_path(repo_path, name);
+    let state_tmp_path = state_path.with_extension("tmp");
+    fs::create_dir_all(state_path.parent().unwrap())?;
+    fs::write(&state_tmp_path, serde_json::to_vec(state)?)?;
+    fs::rename(&state_tmp_path, &state_path)
+}
+
+const REPO_LOCK_NAME: &str = "lock";
+const REPO_LOCK_WAIT_LOG_S: u64 = 60;
+static REPO_LOCK_WAIT_S: AtomicU64 = AtomicU64::new(15 * 60);
+
+/// Limits how long an operation waits for another one holding a repository, 15 minutes when
+/// none
+pub fn set_repo_lock_wait(wait_m: Option<u64>) {
+    REPO_LOCK_WAIT_S.store(wait_m.unwrap_or(15) * 60, Ordering::Relaxed);
+}
+
+/// Advisory lock of a repository, released when dropped
+pub struct RepoLock {
+    _file: fs::File,
+}
+
+/// Takes the lock every archiver operation holds on a repository, waiting for the operation
+/// holding it for a while, and skipping the repository after that
+pub async fn lock_repo(
+    repo_path: &Path,
+    operation: &str,
+    log_target: &mut LogTarget<'_>,
+) -> Option<RepoLock> {
+    lock_repo_until(repo_path, operation, None, log_target).await
+}
+
+/// Same as `lock_repo`, waiting no later than the given deadline
+pub async fn lock_repo_until(
+    repo_path: &Path,
+    operation: &str,
+    deadline: Option<tokio::time::Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> Option<RepoLock> {
+    let lock_path = repo_state_path(repo_path, REPO_LOCK_NAME);
+    let file = fs::create_dir_all(lock_path.parent().unwrap())
+        .and_then(|_| {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+        })
+        .map_err(|e| e.to_string());
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            log(
+                &format!(
+                    "repo-lock {} {} not ok ({})",
+                    repo_path.display(),
+                    operation,
+                    e
+                ),
+                log_target,
+            )
+            .await;
+            return None;
+        }
+    };
+
+    let wait_end = Instant::now() + Duration::from_secs(REPO_LOCK_WAIT_S.load(Ordering::Relaxed));
+    let wait_end = deadline.map_or(wait_end, |x| wait_end.min(x.into_std()));
+    let mut wait_logged: Option<Instant> = None;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(fs::TryLockError::WouldBlock) => (),
+            Err(fs::TryLockError::Error(e)) => {
+                log(
+                    &format!(
+                        "repo-lock {} {} not ok ({})",
+                        repo_path.display(),
+                        operation,
+                        e
+                    ),
+                    log_target,
+                )
+                .await;
+                return None;
+            }
+        }
+        let holder = fs::read_to_string(&lock_path).unwrap_or_default();
+        if Instant::now() >= wait_end {
+            log(
+                &format!(
+                    "repo-lock {} {} skipped, still held by {}",
+                    repo_path.display(),
+                    operation,
+                    holder.trim()
+                ),
+                log_target,
+            )
+            .await;
+            return None;
+        }
+        if wait_logged.is_none_or(|x| x.elapsed() >= Duration::from_secs(REPO_LOCK_WAIT_LOG_S)) {
+            log(
+                &format!(
+                    "repo-lock {} {} waiting, held by {}",
+                    repo_path.display(),
+                    operation,
+                    holder.trim()
+                ),
+                log_target,
+            )
+            .await;
+            wait_logged = Some(Instant::now());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    // tells operations waiting for the lock which one holds it
+    file.set_len(0).ok();
+    writeln!(file, "{} (pid {})", operation, std::process::id()).ok();
+    Some(RepoLock { _file: file })
+}
+
+/// Finds the `.git` of repositories embedded below the given repository, None when the
+/// patterns selecting them are not valid
+pub async fn find_embedded_gits(
+    repo_path: &Path,
+    repo_config: &RepoConfig,
+    log_target: &mut LogTarget<'_>,
+) -> Option<Vec<PathBuf>> {
+    let parse_patterns = |patterns: &Vec<String>| -> Result<Vec<Pattern>, PatternError> {
+        patterns.iter().map(|x| Pattern::new(x)).collect()
+    };
+    let (include_patterns, exclude_patterns) = match (
+        parse_patterns(&repo_config.embedded_git_include),
+        parse_patterns(&repo_config.embedded_git_exclude),
+    ) {
+        (Ok(include_patterns), Ok(exclude_patterns)) => (include_patterns, exclude_patterns),
+        (Err(e), _) | (_, Err(e)) => {
+            log(
+                &format!("embedded-git {} not ok ({})", repo_path.display(), e),
+                log_target,
+            )
+            .await;
+            return None;
+        }
+    };
+
+    let submodule_relpaths: Vec<PathBuf> = match repo_config.embedded_git_skip_submodules {
+        Some(true) => from_utf8(
+            &command_output_quiet(
+                Command::new("git")
+                    .args([
+                        "config",
+                        "--file",
+                        ".gitmodules",
+                        "--null",
+                        "--get-regexp",
+                        r"^submodule\..*\.path$",
+                    ])
+                    .current_dir(repo_path),
+            )
+            .await
+            .map(|x| x.stdout)
+            .unwrap_or_default(),
+        )
+        .unwrap_or_default()
+        .split_terminator('\u{0}')
+        .filter_map(|x| x.split_once('\n'))
+        .map(|(_, path)| PathBuf::from(path))
+        .collect(),
+        _ => vec![],
+    };
+
+    let mut embedded_git_paths: Vec<PathBuf> = vec![];
+    let mut skipped: Vec<(PathBuf, &str)> = vec![];
+    let mut walker = WalkDir::new(repo_path).min_depth(1).into_iter();
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let entry_relpath = entry.path().strip_prefix(repo_path).unwrap().to_path_buf();
+
+        if entry.file_type().is_dir()
+            && exclude_patterns
+                .iter()
+                .any(|x| x.matches_path(&entry_relpath))
+        {
+            walker.skip_current_dir();
+            skipped.push((entry_relpath, "excluded"));
+            continue;
+        }
+        if entry.file_name() != ".git" {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        }
+        if entry.depth() == 1 {
+            continue;
+        }
+
+        let worktree_relpath = entry_relpath.parent().unwrap().to_path_buf();
+        if !include_patterns.is_empty()
+            && !include_patterns
+                .iter()
+                .any(|x| x.matches_path(&worktree_relpath))
+        {
+            skipped.push((worktree_relpath, "not included"));
+        } else if !entry.file_type().is_dir()
+            && repo_config.embedded_git_skip_gitlinks.unwrap_or(false)
+        {
+            skipped.push((worktree_relpath, "gitlink"));
+        } else if submodule_relpaths.contains(&worktree_relpath) {
+            skipped.push((worktree_relpath, "submodule"));
+        } else {
+            embedded_git_paths.push(entry.into_path());
+        }
+    }
+
+    for (relpath, reason) in skipped {
+        log(
+            &format!("skip-embedded-git {} ({})", relpath.display(), reason),
+            log_target,
+        )
+        .await;
+    }
+    for embedded_git_path in &embedded_git_paths {
+        log(
+            &format!(
+                "embedded-git {}",
+                embedded_git_path
+                    .parent()
+                    .unwrap()
+                    .strip_prefix(repo_path)
+                    .unwrap()
+                    .display()
+            ),
+            log_target,
+        )
+        .await;
+    }
+    Some(embedded_git_paths)
+}
+
+static COMMAND_TIMEOUT_S: AtomicU64 = AtomicU64::new(0);
+const COMMAND_TERMINATE_GRACE_S: u64 = 10;
+
+/// Limits how long each external command may run, unlimited when none
+pub fn set_command_timeout(timeout_m: Option<u64>) {
+    COMMAND_TIMEOUT_S.store(timeout_m.unwrap_or(0) * 60, Ordering::Relaxed);
+}
+
+fn command_timeout() -> Option<Duration> {
+    match COMMAND_TIMEOUT_S.load(Ordering::Relaxed) {
+        0 => None,
+        timeout_s => Some(Duration::from_secs(timeout_s)),
+    }
+}
+
+/// The earlier of the given deadline and the command timeout from now
+fn command_deadline(deadline: Option<tokio::time::Instant>) -> Option<tokio::time::Instant> {
+    match (
+        command_timeout().map(|x| tokio::time::Instant::now() + x),
+        deadline,
+    ) {
+        (Some(timeout_deadline), Some(deadline)) => Some(timeout_deadline.min(deadline)),
+        (timeout_deadline, deadline) => timeout_deadline.or(deadline),
+    }
+}
+
+async fn sleep_until_timeout(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Asks the process to stop, killing it when it does not within the grace period
+async fn terminate_child(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+        if tokio::time::timeout(Duration::from_secs(COMMAND_TERMINATE_GRACE_S), child.wait())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+    }
+    child.kill().await.ok();
+}
+
 pub async fn command_output_logfile(
     command: &mut Command,
     status_prefix: String,
     log_target: &mut LogTarget<'_>,
 ) -> bool {
+    command_output_lines(command, status_prefix, log_target)
+        .await
+        .0
+}
+
+/// Same as `command_output_logfile`, stopping the process at the given deadline
+pub async fn command_output_logfile_until(
+    command: &mut Command,
+    status_prefix: String,
+    deadline: Option<tokio::time::Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    command_output_lines_until(command, status_prefix, deadline, log_target)
+        .await
+        .0
+}
+
+/// Same as `command_output_logfile`, also returning the lines written to stdout
+pub async fn command_output_lines(
+    command: &mut Command,
+    status_prefix: String,
+    log_target: &mut LogTarget<'_>,
+) -> (bool, Vec<String>) {
+    command_output_lines_until(command, status_prefix, None, log_target).await
+}
+
+/// Same as `command_output_logfile_until`, returning the exit status, None when the process
+/// was stopped
+pub async fn command_output_status_until(
+    command: &mut Command,
+    status_prefix: String,
+    deadline: Option<tokio::time::Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> Option<ExitStatus> {
+    command_output_until(command, status_prefix, deadline, log_target)
+        .await
+        .0
+}
+
+/// Same as `command_output_lines`, stopping the process at the given deadline
+pub async fn command_output_lines_until(
+    command: &mut Command,
+    status_prefix: String,
+    deadline: Option<tokio::time::Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> (bool, Vec<String>) {
+    let (exit_status, stdout_lines) =
+        command_output_until(command, status_prefix, deadline, log_target).await;
+    (exit_status.is_some_and(|x| x.success()), stdout_lines)
+}
+
+async fn command_output_until(
+    command: &mut Command,
+    status_prefix: String,
+    deadline: Option<tokio::time::Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> (Option<ExitStatus>, Vec<String>) {
     log(&status_prefix, log_target).await;
 
     let mut child = match command
@@ -50,14 +472,22 @@ pub async fn command_output_logfile(
     let stderr = child.stderr.take().expect("no handle to stderr");
     let mut stdout_reader = BufReader::new(stdout).lines();
     let mut stderr_reader = BufReader::new(stderr).lines();
-    let mut success = false;
+    let mut exit_status: Option<ExitStatus> = None;
+    let mut stdout_lines: Vec<String> = vec![];
+    let deadline = command_deadline(deadline);
 
     loop {
         tokio::select! {
+            _ = sleep_until_timeout(deadline) => {
+                terminate_child(&mut child).await;
+                log(&format!("{} timed out", status_prefix), log_target).await;
+                break
+            }
             result = stdout_reader.next_line() => {
                 match result {
                     Ok(Some(line)) => {
                         log(&line, log_target).await;
+                        stdout_lines.push(line);
                     },
                     Err(_) => break,
                     _ => (),
@@ -83,7 +513,7 @@ pub async fn command_output_logfile(
                             }),
                             log_target
                         ).await;
-                        success = exit_code.success();
+                        exit_status = Some(exit_code);
                     },
                     _ => (),
                 }
@@ -91,7 +521,81 @@ pub async fn command_output_logfile(
             }
         };
     }
-    return success;
+    // lines left once the process exited, without waiting on processes it left behind
+    while let Ok(Ok(Some(line))) =
+        tokio::time::timeout(Duration::from_millis(100), stdout_reader.next_line()).await
+    {
+        log(&line, log_target).await;
+        stdout_lines.push(line);
+    }
+    (exit_status, stdout_lines)
+}
+
+/// Output of a started process given its input, stopping it at the given deadline or once it
+/// runs past the command timeout, None when it could not be read or was stopped
+async fn child_output(
+    mut child: Child,
+    input: Option<Vec<u8>>,
+    deadline: Option<tokio::time::Instant>,
+) -> Option<Output> {
+    let stdin = child.stdin.take();
+    let mut stdout = child.stdout.take()?;
+    let mut stderr = child.stderr.take();
+    let mut stdout_bytes: Vec<u8> = vec![];
+    let mut stderr_bytes: Vec<u8> = vec![];
+    let deadline = command_deadline(deadline);
+
+    // input is written while the output is read, both can outgrow the pipe buffers
+    let exit_status = tokio::select! {
+        (_, _, _, exit_status) = async {
+            tokio::join!(
+                async move {
+                    if let (Some(mut stdin), Some(input)) = (stdin, input) {
+                        stdin.write_all(&input).await.ok();
+                    }
+                },
+                stdout.read_to_end(&mut stdout_bytes),
+                async {
+                    if let Some(stderr) = stderr.as_mut() {
+                        stderr.read_to_end(&mut stderr_bytes).await.ok();
+                    }
+                },
+                child.wait()
+            )
+        } => Some(exit_status),
+        _ = sleep_until_timeout(deadline) => None,
+    };
+    match exit_status {
+        Some(exit_status) => Some(Output {
+            status: exit_status.ok()?,
+            stdout: stdout_bytes,
+            stderr: stderr_bytes,
+        }),
+        None => {
+            terminate_child(&mut child).await;
+            None
+        }
+    }
+}
+
+/// Output of a command without logging it, None when it could not run or timed out
+pub async fn command_output_quiet(command: &mut Command) -> Option<Output> {
+    command_output_quiet_until(command, None).await
+}
+
+/// Same as `command_output_quiet`, stopping the process at the given deadline
+pub async fn command_output_quiet_until(
+    command: &mut Command,
+    deadline: Option<tokio::time::Instant>,
+) -> Option<Output> {
+    let child = command
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    child_output(child, None, deadline).await
 }
 
 pub async fn test_available_remotes(
@@ -106,82 +610,83 @@ pub async fn test_available_remotes(
     )
     .await;
 
-    for remote in Vec::from_iter(
-        from_utf8(
-            &Command::new("git")
-                .args(["remote"])
-                .current_dir(repo_path)
-                .kill_on_drop(true)
-                .output()
-                .await
-                .expect("unable to get remote list")
-                .stdout,
+    let Some(remote_output) =
+        command_output_quiet(Command::new("git").args(["remote"]).current_dir(repo_path)).await
+    else {
+        log(
+            &format!("test-available-remotes {} not ok", repo_path.display()),
+            log_target,
         )
-        .unwrap()
-        .trim()
-        .split_whitespace()
-        .map(|x| String::from(x)),
+        .await;
+        return available_remotes;
+    };
+    for remote in Vec::from_iter(
+        from_utf8(&remote_output.stdout)
+            .unwrap_or_default()
+            .trim()
+            .split_whitespace()
+            .map(|x| String::from(x)),
     ) {
-        let remote_url_stdout = &Command::new("git")
-            .args(["remote", "get-url", &remote])
-            .current_dir(repo_path)
-            .output()
-            .await
-            .expect("unable to get remote list")
-            .stdout;
+        let remote_url_stdout = command_output_quiet(
+            Command::new("git")
+                .args(["remote", "get-url", &remote])
+                .current_dir(repo_path),
+        )
+        .await
+        .map(|x| x.stdout)
+        .unwrap_or_default();
 
-        let remote_url = from_utf8(remote_url_stdout).unwrap().trim();
+        let remote_url = from_utf8(&remote_url_stdout).unwrap_or_default().trim();
         if remote_url.starts_with("gcrypt::rsync://") {
             let ls_start = Instant::now();
 
-            let is_ok = &Command::new("git")
-                .args(["ls-remote", "--heads", "--exit-code", &remote_url])
-                .current_dir(repo_path)
-                .output()
-                .await
-                .expect("unable to fetch remote")
-                .status
-                .success();
+            let is_ok = command_output_quiet(
+                Command::new("git")
+                    .args(["ls-remote", "--heads", "--exit-code", remote_url])
+                    .current_dir(repo_path),
+            )
+            .await
+            .is_some_and(|x| x.status.success());
 
             let ls_duration = Instant::now().duration_since(ls_start).as_millis();
-            if *(is_ok) {
+            if is_ok {
                 let cost = 200 + ls_duration / 100;
-                Command::new("git")
-                    .args([
-                        "config",
-                        "--replace-all",
-                        &format!("remote.{}.annex-cost", remote),
-                        &format!("{}", cost),
-                    ])
-                    .current_dir(repo_path)
-                    .output()
-                    .await
-                    .unwrap();
-                Command::new("git")
-                    .args([
-                        "config",
-                        "--replace-all",
-                        &format!("remote.{}.annex-ignore", remote),
-                        "false",
-                    ])
-                    .current_dir(repo_path)
-                    .output()
-                    .await
-                    .unwrap();
+                command_output_quiet(
+                    Command::new("git")
+                        .args([
+                            "config",
+                            "--replace-all",
+                            &format!("remote.{}.annex-cost", remote),
+                            &format!("{}", cost),
+                        ])
+                        .current_dir(repo_path),
+                )
+                .await;
+                command_output_quiet(
+                    Command::new("git")
+                        .args([
+                            "config",
+                            "--replace-all",
+                            &format!("remote.{}.annex-ignore", remote),
+                            "false",
+                        ])
+                        .current_dir(repo_path),
+                )
+                .await;
                 log(&format!("{} ({}) ok", remote, cost), log_target).await;
                 available_remotes.push(remote);
             } else {
-                Command::new("git")
-                    .args([
-                        "config",
-                        "--replace-all",
-                        &format!("remote.{}.annex-ignore", remote),
-                        "true",
-                    ])
-                    .current_dir(repo_path)
-                    .output()
-                    .await
-                    .unwrap();
+                command_output_quiet(
+                    Command::new("git")
+                        .args([
+                            "config",
+                            "--replace-all",
+                            &format!("remote.{}.annex-ignore", remote),
+                            "true",
+                        ])
+                        .current_dir(repo_path),
+                )
+                .await;
                 log(&format!("{} not ok", remote), log_target).await;
             }
         } else {
@@ -197,3 +702,39 @@ pub async fn test_available_remotes(
     .await;
     available_remotes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_output_quiet_until_terminates_at_deadline() {
+        let marker_path = std::env::temp_dir().join(format!(
+            "git-annex-archiver-test-terminate-{}",
+            std::process::id()
+        ));
+        fs::remove_file(&marker_path).ok();
+        let output = command_output_quiet_until(
+            Command::new("sh").args([
+                "-c",
+                &format!(
+                    "trap 'touch {:?}; exit 1' TERM; sleep 30 & wait",
+                    marker_path
+                ),
+            ]),
+            Some(tokio::time::Instant::now() + Duration::from_secs(1)),
+        )
+        .await;
+        assert!(output.is_none());
+        assert!(fs::remove_file(&marker_path).is_ok());
+
+        let output = command_output_quiet(Command::new("sh").args(["-c", "echo a; echo b >&2"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            (output.stdout, output.stderr),
+            (b"a\n".to_vec(), b"b\n".to_vec())
+        );
+    }
+}
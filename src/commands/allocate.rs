@@ -1,8 +1,22 @@
-use chrono::{DateTime, Local};
+use glob::{MatchOptions, Pattern};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::{path::PathBuf, str::from_utf8};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::time::{Duration, SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::from_utf8,
+};
 use tokio::process::Command;
+use walkdir::WalkDir;
+
+use crate::config::{AllocateQuotaPolicy, AllocateRule, AllocateRuleAction, Config};
+
+#[cfg(target_os = "linux")]
+use crate::platform::linux::{has_file_drop_attr, set_file_drop_attr, unset_file_drop_attr};
 
 #[cfg(target_os = "macos")]
 use crate::platform::macos::{has_file_drop_attr, set_file_drop_attr, unset_file_drop_attr};
@@ -10,18 +24,794 @@ use crate::platform::macos::{has_file_drop_attr, set_file_drop_attr, unset_file_
 #[cfg(target_os = "windows")]
 use crate::platform::windows::{has_file_drop_attr, set_file_drop_attr, unset_file_drop_attr};
 
-use super::{command_output_logfile, log, test_available_remotes, LogTarget};
+use super::{
+    child_output, command_output_logfile, command_output_quiet, lock_repo, log, read_repo_state,
+    test_available_remotes, write_repo_state, LogTarget,
+};
 
 #[derive(Serialize, Deserialize)]
-struct AnnexLog {
+struct AnnexFind {
     file: String,
+    key: String,
+    bytesize: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct AllocateIndex {
+    commit: Option<String>,
+    /// git-annex branch commit the dropped states were read at
+    annex_commit: Option<String>,
+    /// Modification times of the directories holding tracked files, by relative path
+    #[serde(default)]
+    dir_mtimes_ns: HashMap<PathBuf, i64>,
+    entries: HashMap<PathBuf, AllocateIndexEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AllocateIndexEntry {
+    key: Option<String>,
+    mtime_ns: i64,
+    ctime_ns: i64,
+    is_dropped: bool,
 }
 
 static GET_MAX_CT: usize = 4;
+static ALLOCATE_INDEX_NAME: &str = "allocate-index.json";
+
+/// Modification and status change times, the latter also changing with tags
+fn file_times_ns(file_path: &Path) -> Option<(i64, i64)> {
+    let metadata = file_path.metadata().ok()?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((
+            metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec(),
+        ))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let mtime_ns = metadata
+            .modified()
+            .ok()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()?
+            .as_nanos() as i64;
+        Some((mtime_ns, mtime_ns))
+    }
+}
+
+async fn git_head_commit(repo_path: &PathBuf) -> Option<String> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["rev-parse", "--verify", "-q", "HEAD"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(String::from(from_utf8(&output.stdout).ok()?.trim()))
+}
+
+/// Paths changed between two commits, renames as both their paths, None when either is gone
+async fn git_diff_paths(
+    repo_path: &PathBuf,
+    from_commit: &str,
+    to_commit: &str,
+) -> Option<HashSet<PathBuf>> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args([
+                "diff",
+                "--name-only",
+                "--no-renames",
+                "-z",
+                from_commit,
+                to_commit,
+            ])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(nul_separated_paths(&output.stdout).collect())
+}
+
+async fn git_annex_commit(repo_path: &PathBuf) -> Option<String> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["rev-parse", "--verify", "-q", "refs/heads/git-annex"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(from_utf8(&output.stdout).ok()?.trim().to_string())
+}
+
+/// Keys whose location log changed between two git-annex branch commits
+async fn annex_changed_keys(
+    repo_path: &PathBuf,
+    from_commit: &str,
+    to_commit: &str,
+) -> Option<HashSet<String>> {
+    Some(
+        git_diff_paths(repo_path, from_commit, to_commit)
+            .await?
+            .iter()
+            .filter_map(|x| x.file_name()?.to_str()?.strip_suffix(".log"))
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Path as git printed it, byte for byte where paths need not be UTF-8
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).as_ref())
+}
+
+/// Paths printed separated by NUL bytes
+fn nul_separated_paths(stdout: &[u8]) -> impl Iterator<Item = PathBuf> + '_ {
+    stdout
+        .split(|x| *x == 0)
+        .filter(|x| !x.is_empty())
+        .map(path_from_bytes)
+}
+
+/// Path arguments in chunks short enough for a command line, one empty chunk for all paths
+fn path_arg_chunks<'a>(file_paths: Option<&[&'a Path]>) -> Vec<Vec<&'a Path>> {
+    match file_paths {
+        Some(file_paths) => file_paths.chunks(1000).map(|x| x.to_vec()).collect(),
+        None => vec![vec![]],
+    }
+}
+
+/// Tracked paths still in the worktree, among the given paths or all of them
+async fn git_tracked_paths(
+    repo_path: &PathBuf,
+    file_paths: Option<&[&Path]>,
+) -> Option<HashSet<PathBuf>> {
+    let mut tracked_paths = HashSet::<PathBuf>::new();
+    for file_paths_chunk in path_arg_chunks(file_paths) {
+        let output = command_output_quiet(
+            Command::new("git")
+                .args(["--literal-pathspecs", "ls-files", "-z", "--"])
+                .args(file_paths_chunk)
+                .current_dir(repo_path),
+        )
+        .await
+        .filter(|x| x.status.success())?;
+        tracked_paths
+            .extend(nul_separated_paths(&output.stdout).filter(|x| repo_path.join(x).exists()));
+    }
+    Some(tracked_paths)
+}
+
+/// Files whose content is not here, among the given paths or all of them, None when they
+/// could not be listed
+async fn annex_dropped_paths(
+    repo_path: &PathBuf,
+    file_paths: Option<&[&Path]>,
+) -> Option<HashSet<PathBuf>> {
+    let mut dropped_paths = HashSet::<PathBuf>::new();
+    for file_paths_chunk in path_arg_chunks(file_paths) {
+        dropped_paths.extend(
+            annex_find_paths(repo_path, &["--not", "--in=here", "--"], &file_paths_chunk).await?,
+        );
+    }
+    Some(dropped_paths)
+}
+
+/// Files in the indexed directories whose entries changed since they were indexed, along with
+/// the indexed files they held, and the current modification times of those directories
+fn changed_dir_paths(
+    repo_path: &Path,
+    index: &AllocateIndex,
+) -> (HashSet<PathBuf>, HashMap<PathBuf, i64>) {
+    let dir_mtimes_ns: HashMap<PathBuf, i64> = index
+        .dir_mtimes_ns
+        .keys()
+        .filter_map(|x| Some((x.clone(), file_times_ns(&repo_path.join(x))?.0)))
+        .collect();
+    let changed_dirs: HashSet<&PathBuf> = index
+        .dir_mtimes_ns
+        .iter()
+        .filter(|(dir, mtime_ns)| dir_mtimes_ns.get(*dir) != Some(mtime_ns))
+        .map(|(dir, _)| dir)
+        .collect();
+
+    let mut changed_paths = HashSet::<PathBuf>::new();
+    for changed_dir in &changed_dirs {
+        let Ok(dir_entries) = fs::read_dir(repo_path.join(changed_dir)) else {
+            continue;
+        };
+        for dir_entry in dir_entries.filter_map(Result::ok) {
+            let path = changed_dir.join(dir_entry.file_name());
+            if !dir_entry.file_type().is_ok_and(|x| x.is_dir()) {
+                changed_paths.insert(path);
+            } else if dir_entry.file_name() != ".git" && !index.dir_mtimes_ns.contains_key(&path) {
+                // Everything in a new directory is new
+                changed_paths.extend(
+                    WalkDir::new(repo_path.join(&path))
+                        .into_iter()
+                        .filter_entry(|x| x.file_name() != ".git")
+                        .filter_map(Result::ok)
+                        .filter(|x| !x.file_type().is_dir())
+                        .filter_map(|x| Some(x.path().strip_prefix(repo_path).ok()?.to_path_buf())),
+                );
+            }
+        }
+    }
+    changed_paths.extend(
+        index
+            .entries
+            .keys()
+            .filter(|x| {
+                x.parent()
+                    .is_some_and(|x| changed_dirs.contains(&x.to_path_buf()))
+            })
+            .cloned(),
+    );
+    (changed_paths, dir_mtimes_ns)
+}
+
+/// Modification times of the directories holding tracked paths, keeping those already read
+fn tracked_dir_mtimes_ns(
+    repo_path: &Path,
+    tracked_paths: &HashSet<PathBuf>,
+    mut dir_mtimes_ns: HashMap<PathBuf, i64>,
+) -> HashMap<PathBuf, i64> {
+    let tracked_dirs: HashSet<&Path> = tracked_paths
+        .iter()
+        .flat_map(|x| x.ancestors().skip(1))
+        .collect();
+    dir_mtimes_ns.retain(|x, _| tracked_dirs.contains(x.as_path()));
+    for tracked_dir in tracked_dirs {
+        if !dir_mtimes_ns.contains_key(tracked_dir) {
+            if let Some((mtime_ns, _)) = file_times_ns(&repo_path.join(tracked_dir)) {
+                dir_mtimes_ns.insert(tracked_dir.to_path_buf(), mtime_ns);
+            }
+        }
+    }
+    dir_mtimes_ns
+}
+
+async fn annex_find(
+    repo_path: &PathBuf,
+    args: &[&str],
+    file_paths: &[impl AsRef<OsStr>],
+) -> Option<Vec<AnnexFind>> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args([&["annex", "find", "--json"], args].concat())
+            .args(file_paths)
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(
+        from_utf8(&output.stdout)
+            .ok()?
+            .trim()
+            .split_terminator("\n")
+            .filter_map(|x| serde_json::from_str(x).ok())
+            .collect(),
+    )
+}
+
+async fn annex_find_paths(
+    repo_path: &PathBuf,
+    args: &[&str],
+    file_paths: &[impl AsRef<OsStr>],
+) -> Option<HashSet<PathBuf>> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args([&["annex", "find", "--print0"], args].concat())
+            .args(file_paths)
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(nul_separated_paths(&output.stdout).collect())
+}
+
+async fn annex_keys(
+    repo_path: &PathBuf,
+    file_paths: &[&PathBuf],
+) -> Option<HashMap<PathBuf, String>> {
+    let mut keys = HashMap::<PathBuf, String>::new();
+    for file_paths_chunk in file_paths.chunks(1000) {
+        for found in annex_find(repo_path, &["--include=*", "--"], file_paths_chunk).await? {
+            keys.insert(PathBuf::from(found.file), found.key);
+        }
+    }
+    Some(keys)
+}
+
+/// Contents of the objects `git cat-file --batch` printed, in the order they were asked for,
+/// None for those missing
+fn parse_cat_file_batch(mut stdout: &[u8]) -> Option<Vec<Option<Vec<u8>>>> {
+    let mut contents = Vec::<Option<Vec<u8>>>::new();
+    while !stdout.is_empty() {
+        let header_end = stdout.iter().position(|x| *x == b'\n')?;
+        let header = from_utf8(&stdout[..header_end]).ok()?;
+        stdout = &stdout[header_end + 1..];
+        match header.rsplit_once(' ') {
+            Some((_, "missing" | "ambiguous")) => contents.push(None),
+            Some((_, size)) => {
+                let size = size.parse::<usize>().ok()?;
+                contents.push(Some(stdout.get(..size)?.to_vec()));
+                stdout = stdout.get(size + 1..)?;
+            }
+            None => return None,
+        }
+    }
+    Some(contents)
+}
+
+/// Contents of the given paths as committed in HEAD, all read by a single process
+async fn git_head_contents(
+    repo_path: &PathBuf,
+    file_paths: &[&PathBuf],
+) -> Option<Vec<Option<Vec<u8>>>> {
+    if file_paths.is_empty() {
+        return Some(vec![]);
+    }
+    let child = Command::new("git")
+        .args(["cat-file", "--batch"])
+        .current_dir(repo_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .ok()?;
+    let input: Vec<u8> = file_paths
+        .iter()
+        .flat_map(|x| [b"HEAD:", x.as_os_str().as_encoded_bytes(), b"\n"].concat())
+        .collect();
+    let output = child_output(child, Some(input), None).await?;
+    let contents = parse_cat_file_batch(&output.stdout)?;
+    match contents.len() == file_paths.len() {
+        true => Some(contents),
+        false => None,
+    }
+}
+
+/// Reasons why paths differ from HEAD, either in git or in the annex key of their content
+async fn git_uncommitted_reasons(
+    repo_path: &PathBuf,
+    file_paths: &[&PathBuf],
+) -> HashMap<PathBuf, String> {
+    let mut reasons = HashMap::<PathBuf, String>::new();
+    for file_paths_chunk in file_paths.chunks(1000) {
+        let status_output = command_output_quiet(
+            Command::new("git")
+                .args([
+                    "--literal-pathspecs",
+                    "status",
+                    "--porcelain",
+                    "-z",
+                    "--untracked-files=no",
+                    "--",
+                ])
+                .args(file_paths_chunk)
+                .current_dir(repo_path),
+        )
+        .await
+        .filter(|x| x.status.success());
+        let (Some(status_output), Some(working_keys)) =
+            (status_output, annex_keys(repo_path, file_paths_chunk).await)
+        else {
+            // Nothing is sent without knowing it is committed
+            for file_path in file_paths_chunk {
+                reasons.insert(
+                    PathBuf::from(file_path),
+                    String::from("status not available"),
+                );
+            }
+            continue;
+        };
+        let mut status_entries = status_output.stdout.split(|x| *x == 0);
+        while let Some(status_entry) = status_entries.next() {
+            if status_entry.len() < 4 {
+                continue;
+            }
+            let (status, path) = status_entry.split_at(3);
+            let status: Vec<char> = status.iter().map(|x| char::from(*x)).collect();
+            if status[0] == 'R' || status[0] == 'C' {
+                // Renames and copies are followed by their original path
+                status_entries.next();
+            }
+            reasons.insert(
+                path_from_bytes(path),
+                match status[0] {
+                    ' ' => format!("modified in worktree, {}", status[1]),
+                    _ => format!("staged, {}", status[0]),
+                },
+            );
+        }
+
+        let key_paths: Vec<&PathBuf> = file_paths_chunk
+            .iter()
+            .filter(|x| !reasons.contains_key(**x) && working_keys.contains_key(**x))
+            .copied()
+            .collect();
+        let Some(head_contents) = git_head_contents(repo_path, &key_paths).await else {
+            for key_path in key_paths {
+                reasons.insert(key_path.clone(), String::from("HEAD content not readable"));
+            }
+            continue;
+        };
+        for (key_path, head_content) in key_paths.into_iter().zip(head_contents) {
+            // Both symlinks and pointer files end with the key
+            let head_key = head_content.map(|x| {
+                String::from_utf8_lossy(&x)
+                    .trim()
+                    .rsplit('/')
+                    .next()
+                    .map(String::from)
+                    .unwrap_or_default()
+            });
+            if head_key.as_ref() != working_keys.get(key_path) {
+                reasons.insert(
+                    key_path.clone(),
+                    String::from("annex key differs from HEAD"),
+                );
+            }
+        }
+    }
+    reasons
+}
+
+async fn annex_numcopies(repo_path: &PathBuf) -> Option<u64> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["annex", "numcopies"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(
+        from_utf8(&output.stdout)
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .unwrap_or(1),
+    )
+}
+
+async fn annex_drop(repo_path: &PathBuf, file_path: &Path, log_target: &mut LogTarget<'_>) -> bool {
+    command_output_logfile(
+        Command::new("git")
+            .args(["annex", "drop", &format!("{}", file_path.display())])
+            .current_dir(repo_path),
+        format!("git-annex-drop {:?}", repo_path.display()),
+        log_target,
+    )
+    .await
+}
+
+async fn annex_get(repo_path: &PathBuf, file_path: &Path, log_target: &mut LogTarget<'_>) -> bool {
+    let mut is_command_ok: bool = false;
+    let mut get_ct = 0;
+
+    while get_ct < GET_MAX_CT && !is_command_ok {
+        is_command_ok = command_output_logfile(
+            Command::new("git")
+                .args(["annex", "get", &format!("{}", file_path.display())])
+                .current_dir(repo_path),
+            format!("git-annex-get {:?}", repo_path.display()),
+            log_target,
+        )
+        .await;
+        get_ct += 1;
+    }
+    is_command_ok
+}
+
+/// Splits matching options like shell words, None when a quote is left open
+fn split_matching_options(matching: &str) -> Option<Vec<String>> {
+    let mut options = Vec::<String>::new();
+    let mut option: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = matching.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => option.get_or_insert_default().push(chars.next()?),
+            (Some(_), c) => option.get_or_insert_default().push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                option.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => options.extend(option.take()),
+            (None, c) => option.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    options.extend(option);
+    Some(options)
+}
+
+fn matches_rule_path(pattern: &Pattern, path: &Path) -> bool {
+    pattern.matches_path_with(
+        path,
+        MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        },
+    )
+}
+
+/// Tracked paths a rule applies to, None when the rule is not valid
+async fn allocate_rule_paths(
+    repo_path: &PathBuf,
+    rule: &AllocateRule,
+    tracked_paths: &HashSet<PathBuf>,
+    log_target: &mut LogTarget<'_>,
+) -> Option<HashSet<PathBuf>> {
+    let mut rule_paths = tracked_paths.clone();
+
+    if let Some(path_pattern) = &rule.path {
+        match Pattern::new(path_pattern) {
+            Ok(pattern) => rule_paths.retain(|x| matches_rule_path(&pattern, x)),
+            Err(e) => {
+                log(&format!("rule {} not ok ({})", path_pattern, e), log_target).await;
+                return None;
+            }
+        }
+    }
+    if let Some(matching) = &rule.matching {
+        let Some(matching_options) = split_matching_options(matching) else {
+            log(
+                &format!("rule {} not ok (unterminated quote or escape)", matching),
+                log_target,
+            )
+            .await;
+            return None;
+        };
+        let Some(matching_paths) = annex_find_paths(
+            repo_path,
+            &matching_options
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<&str>>(),
+            &[] as &[&Path],
+        )
+        .await
+        else {
+            log(&format!("rule {} not ok", matching), log_target).await;
+            return None;
+        };
+        rule_paths.retain(|x| matching_paths.contains(x));
+    }
+    if let Some(older_than_d) = rule.older_than_d {
+        let older_than_time = SystemTime::now() - Duration::from_secs(older_than_d * 24 * 60 * 60);
+        rule_paths.retain(|x| {
+            repo_path
+                .join(x)
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| modified < older_than_time)
+                .unwrap_or(false)
+        });
+    }
+    Some(rule_paths)
+}
+
+async fn allocate_rules(
+    repo_path: &PathBuf,
+    rules: &[AllocateRule],
+    pinned_paths: &HashSet<PathBuf>,
+    tracked_paths: &HashSet<PathBuf>,
+    tracked_dropped_paths: &HashSet<PathBuf>,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    log(
+        &format!("allocate-rules {}", repo_path.display()),
+        log_target,
+    )
+    .await;
+
+    let mut is_ok = true;
+    let mut drop_paths = HashSet::<PathBuf>::new();
+    for rule in rules
+        .iter()
+        .filter(|rule| rule.action == AllocateRuleAction::Drop)
+    {
+        match allocate_rule_paths(repo_path, rule, tracked_paths, log_target).await {
+            Some(rule_paths) => drop_paths.extend(rule_paths),
+            None => is_ok = false,
+        }
+    }
+
+    let get_paths: Vec<&PathBuf> = pinned_paths.intersection(tracked_dropped_paths).collect();
+    let drop_paths: Vec<&PathBuf> = drop_paths
+        .iter()
+        .filter(|x| !pinned_paths.contains(*x) && !tracked_dropped_paths.contains(*x))
+        .collect();
+    log(
+        &format!(
+            "files to move, pinned ({}), dropped ({})",
+            get_paths.len(),
+            drop_paths.len()
+        ),
+        log_target,
+    )
+    .await;
+
+    if !get_paths.is_empty() || !drop_paths.is_empty() {
+        test_available_remotes(repo_path, log_target).await;
+    }
+    for get_path in get_paths {
+        if annex_get(repo_path, get_path, log_target).await {
+            unset_file_drop_attr(&repo_path.join(get_path), log_target).await;
+        } else {
+            is_ok = false;
+        }
+    }
+    for drop_path in drop_paths {
+        if annex_drop(repo_path, drop_path, log_target).await {
+            set_file_drop_attr(&repo_path.join(drop_path), log_target).await;
+        } else {
+            is_ok = false;
+        }
+    }
+
+    log(
+        &format!(
+            "allocate-rules {} {}",
+            repo_path.display(),
+            match is_ok {
+                true => "ok",
+                false => "not ok",
+            }
+        ),
+        log_target,
+    )
+    .await;
+    is_ok
+}
+
+/// Orders the files to drop first according to the quota policy
+fn sort_quota_candidates(
+    repo_path: &Path,
+    candidate_files: &mut [AnnexFind],
+    quota_policy: AllocateQuotaPolicy,
+) {
+    match quota_policy {
+        AllocateQuotaPolicy::Accessed => candidate_files.sort_by_cached_key(|x| {
+            repo_path
+                .join(&x.file)
+                .metadata()
+                .and_then(|metadata| metadata.accessed())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        }),
+        AllocateQuotaPolicy::Largest => {
+            candidate_files.sort_by_cached_key(|x| Reverse(x.bytesize.parse::<u64>().unwrap_or(0)))
+        }
+        AllocateQuotaPolicy::Oldest => candidate_files.sort_by_cached_key(|x| {
+            repo_path
+                .join(&x.file)
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        }),
+    }
+}
+
+async fn allocate_quota(
+    repo_path: &PathBuf,
+    quota_mb: u64,
+    quota_policy: AllocateQuotaPolicy,
+    pinned_paths: &HashSet<PathBuf>,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    log(
+        &format!("allocate-quota {}", repo_path.display()),
+        log_target,
+    )
+    .await;
+
+    let quota_b = quota_mb * 1_000_000;
+    let Some(present_files) = annex_find(repo_path, &["--in=here"], &[] as &[&Path]).await else {
+        log(
+            &format!("allocate-quota {} not ok", repo_path.display()),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    let mut present_b: u64 = present_files
+        .iter()
+        .map(|x| x.bytesize.parse::<u64>().unwrap_or(0))
+        .sum();
+    log(
+        &format!(
+            "present files ({} of {} MB)",
+            present_b / 1_000_000,
+            quota_mb
+        ),
+        log_target,
+    )
+    .await;
+
+    if present_b > quota_b {
+        test_available_remotes(repo_path, log_target).await;
+
+        // Only files still satisfying numcopies once dropped from here, git-annex checks it again
+        let candidate_files = match annex_numcopies(repo_path).await {
+            Some(numcopies) => {
+                annex_find(
+                    repo_path,
+                    &["--in=here", &format!("--copies={}", numcopies + 1)],
+                    &[] as &[&Path],
+                )
+                .await
+            }
+            None => None,
+        };
+        let Some(mut candidate_files) = candidate_files else {
+            log(
+                &format!("allocate-quota {} not ok", repo_path.display()),
+                log_target,
+            )
+            .await;
+            return false;
+        };
+        candidate_files.retain(|x| !pinned_paths.contains(&PathBuf::from(&x.file)));
+        sort_quota_candidates(repo_path, &mut candidate_files, quota_policy);
+        log(
+            &format!("files to drop, candidates ({})", candidate_files.len()),
+            log_target,
+        )
+        .await;
+
+        for candidate_file in candidate_files {
+            if present_b <= quota_b {
+                break;
+            }
+            let candidate_path = PathBuf::from(&candidate_file.file);
+            if annex_drop(repo_path, &candidate_path, log_target).await {
+                set_file_drop_attr(&repo_path.join(&candidate_path), log_target).await;
+                present_b =
+                    present_b.saturating_sub(candidate_file.bytesize.parse::<u64>().unwrap_or(0));
+            }
+        }
+    }
+
+    let is_ok = present_b <= quota_b;
+    log(
+        &format!(
+            "allocate-quota {} {}",
+            repo_path.display(),
+            match is_ok {
+                true => String::from("ok"),
+                false => format!(
+                    "not ok ({} MB over quota)",
+                    (present_b - quota_b) / 1_000_000
+                ),
+            }
+        ),
+        log_target,
+    )
+    .await;
+    is_ok
+}
 
 pub async fn allocate(
-    repo_paths: &Vec<PathBuf>,
-    received_since: Option<DateTime<Local>>,
+    repo_paths: &[PathBuf],
+    file_paths: Option<&Vec<PathBuf>>,
+    config: &Config,
     log_target: &mut LogTarget<'_>,
     notify_progress: impl Fn(String),
 ) -> bool {
@@ -29,102 +819,193 @@ pub async fn allocate(
     for (repo_index, repo_path) in repo_paths.iter().enumerate() {
         notify_progress(format!("{}/{}", repo_index + 1, repo_paths.len()));
 
+        // Targeted allocation only looks at the given paths, skipping repositories without any
+        let repo_file_paths: Option<Vec<&Path>> = file_paths.map(|file_paths| {
+            file_paths
+                .iter()
+                .filter_map(|x| x.strip_prefix(repo_path).ok())
+                .collect()
+        });
+        if repo_file_paths
+            .as_ref()
+            .is_some_and(|repo_file_paths| repo_file_paths.is_empty())
+        {
+            continue;
+        }
+
+        let Some(_repo_lock) = lock_repo(repo_path, "allocate", log_target).await else {
+            is_ok = false;
+            continue;
+        };
         let mut is_repo_ok: bool = true;
+        let repo_config = config.repo_config(repo_path);
         log(
             &format!("allocate-repo-files {}", repo_path.display()),
             log_target,
         )
         .await;
 
-        let tracked_paths = HashSet::<PathBuf>::from_iter(
-            from_utf8(
-                &Command::new("git")
-                    .args(["ls-files", "-z"])
-                    .current_dir(repo_path)
-                    .output()
-                    .await
-                    .expect("unable to get file list")
-                    .stdout,
+        let mut index: AllocateIndex = read_repo_state(repo_path, ALLOCATE_INDEX_NAME);
+        let head_commit = git_head_commit(repo_path).await;
+        let annex_commit = git_annex_commit(repo_path).await;
+
+        // Paths changed by git or git-annex since the index commits, their drop tag follows them
+        let committed_paths = match (&index.commit, &head_commit) {
+            (Some(index_commit), Some(head_commit)) if index_commit != head_commit => {
+                git_diff_paths(repo_path, index_commit, head_commit).await
+            }
+            (Some(_), Some(_)) => Some(HashSet::<PathBuf>::new()),
+            _ => None,
+        };
+        let changed_keys = match (&index.annex_commit, &annex_commit) {
+            (Some(index_annex_commit), Some(annex_commit))
+                if index_annex_commit != annex_commit =>
+            {
+                annex_changed_keys(repo_path, index_annex_commit, annex_commit).await
+            }
+            (Some(_), Some(_)) => Some(HashSet::<String>::new()),
+            _ => None,
+        };
+        let (changed_dir_paths, dir_mtimes_ns) = changed_dir_paths(repo_path, &index);
+
+        // Only paths which may have changed since the index was written are listed, all of them
+        // when there is no index to compare with
+        let candidate_paths: Option<HashSet<PathBuf>> =
+            match (&repo_file_paths, &committed_paths, &changed_keys) {
+                (Some(repo_file_paths), _, _) => {
+                    Some(repo_file_paths.iter().map(|x| x.to_path_buf()).collect())
+                }
+                (None, Some(committed_paths), Some(changed_keys)) => Some(
+                    index
+                        .entries
+                        .iter()
+                        .filter(|(_, entry)| {
+                            entry.key.as_ref().is_some_and(|x| changed_keys.contains(x))
+                        })
+                        .map(|(x, _)| x.clone())
+                        .chain(committed_paths.iter().cloned())
+                        .chain(changed_dir_paths)
+                        .collect(),
+                ),
+                _ => None,
+            };
+        let candidate_args: Option<Vec<&Path>> = candidate_paths
+            .as_ref()
+            .map(|x| x.iter().map(PathBuf::as_path).collect());
+
+        let Some(listed_paths) = git_tracked_paths(repo_path, candidate_args.as_deref()).await
+        else {
+            log(
+                &format!("allocate-repo-files {} not ok", repo_path.display()),
+                log_target,
             )
-            .unwrap()
-            .trim()
-            .split_terminator("\u{0}")
-            .filter(|x| repo_path.join(x).try_exists().unwrap())
-            .map(|x| PathBuf::from(x)),
-        );
+            .await;
+            is_ok = false;
+            continue;
+        };
         log("tracked paths ok", log_target).await;
 
-        let tracked_dropped_paths = HashSet::<PathBuf>::from_iter(
-            from_utf8(
-                &Command::new("git")
-                    .args(["annex", "find", "--not", "--in=here", "--print0"])
-                    .current_dir(repo_path)
-                    .output()
-                    .await
-                    .expect("unable to get file list")
-                    .stdout,
+        let listed_args: Option<Vec<&Path>> = candidate_paths
+            .as_ref()
+            .map(|_| listed_paths.iter().map(PathBuf::as_path).collect());
+        let Some(mut tracked_dropped_paths) =
+            annex_dropped_paths(repo_path, listed_args.as_deref()).await
+        else {
+            log(
+                &format!("allocate-repo-files {} not ok", repo_path.display()),
+                log_target,
             )
-            .unwrap()
-            .trim()
-            .split_terminator("\u{0}")
-            .map(|x| PathBuf::from(x)),
-        );
+            .await;
+            is_ok = false;
+            continue;
+        };
         log("tracked dropped paths ok", log_target).await;
 
-        let received_paths = match received_since {
-            None => tracked_paths.clone(),
-            Some(received_since) => {
-                let since_arg = format!("{}", received_since.format("%Y-%m-%d %H:%M:%S"));
-                let modified_paths = tracked_paths
-                    .iter()
-                    .filter(|x| {
-                        DateTime::<chrono::Local>::from(
-                            repo_path.join(x).metadata().unwrap().modified().unwrap(),
-                        ) > received_since
-                    })
-                    .map(|x| x.as_os_str().to_str().unwrap())
-                    .collect::<Vec<&str>>();
-
-                match modified_paths.is_empty() {
-                    true => HashSet::<PathBuf>::new(),
-                    false => HashSet::<PathBuf>::from_iter(
-                        from_utf8(
-                            &Command::new("git")
-                                .args(
-                                    [
-                                        vec![
-                                            "annex",
-                                            "log",
-                                            "--json",
-                                            "--since",
-                                            &since_arg,
-                                            "--in=here",
-                                            "--or",
-                                            "--in",
-                                            &format!("here@{}", since_arg),
-                                        ],
-                                        modified_paths,
-                                    ]
-                                    .concat(),
-                                )
-                                .current_dir(repo_path)
-                                .output()
-                                .await
-                                .expect("unable to get file list")
-                                .stdout,
-                        )
-                        .unwrap()
-                        .trim()
-                        .split_terminator("\n")
-                        .map(|x| {
-                            let v: AnnexLog = serde_json::from_str(x).unwrap();
-                            PathBuf::from(v.file)
-                        }),
-                    ),
+        // Paths outside the candidates keep their indexed state
+        let mut tracked_paths = listed_paths.clone();
+        if let (None, Some(candidate_paths)) = (&repo_file_paths, &candidate_paths) {
+            for (indexed_path, entry) in &index.entries {
+                if !candidate_paths.contains(indexed_path) {
+                    tracked_paths.insert(indexed_path.clone());
+                    if entry.is_dropped {
+                        tracked_dropped_paths.insert(indexed_path.clone());
+                    }
                 }
             }
+        }
+        let dir_mtimes_ns = tracked_dir_mtimes_ns(repo_path, &tracked_paths, dir_mtimes_ns);
+
+        // Without every pinned path, neither rules nor quota may drop anything
+        let mut pinned_paths = HashSet::<PathBuf>::new();
+        let mut are_pinned_paths_ok = true;
+        for rule in repo_config
+            .allocate_rules
+            .iter()
+            .filter(|rule| rule.action == AllocateRuleAction::Keep)
+        {
+            match allocate_rule_paths(repo_path, rule, &tracked_paths, log_target).await {
+                Some(rule_paths) => pinned_paths.extend(rule_paths),
+                None => are_pinned_paths_ok = false,
+            }
+        }
+        log(
+            &format!(
+                "pinned paths {} ({})",
+                match are_pinned_paths_ok {
+                    true => "ok",
+                    false => "not ok",
+                },
+                pinned_paths.len()
+            ),
+            log_target,
+        )
+        .await;
+        if !are_pinned_paths_ok {
+            is_repo_ok = false;
+        }
+
+        // Drop tags only change the status change time of their file, so without a watcher
+        // catching them every indexed file is looked at
+        let examined_tracked_paths = match config.allocate_watch.unwrap_or(false) {
+            true => &listed_paths,
+            false => &tracked_paths,
         };
-        log("received paths ok", log_target).await;
+        let mut received_paths = HashSet::<PathBuf>::new();
+        let mut send_paths = HashSet::<PathBuf>::new();
+        for tracked_path in examined_tracked_paths {
+            match index.entries.get(tracked_path) {
+                _ if repo_file_paths.is_some() => {
+                    send_paths.insert(tracked_path.clone());
+                }
+                None => {
+                    received_paths.insert(tracked_path.clone());
+                }
+                Some(entry)
+                    if entry.is_dropped != tracked_dropped_paths.contains(tracked_path)
+                        || committed_paths
+                            .as_ref()
+                            .is_some_and(|x| x.contains(tracked_path)) =>
+                {
+                    received_paths.insert(tracked_path.clone());
+                }
+                Some(entry)
+                    if file_times_ns(&repo_path.join(tracked_path))
+                        != Some((entry.mtime_ns, entry.ctime_ns)) =>
+                {
+                    send_paths.insert(tracked_path.clone());
+                }
+                _ => (),
+            }
+        }
+        log(
+            &format!(
+                "changed paths ok ({} of {})",
+                received_paths.len() + send_paths.len(),
+                tracked_paths.len()
+            ),
+            log_target,
+        )
+        .await;
 
         log(
             &format!("moved files ({})", received_paths.len()),
@@ -139,81 +1020,64 @@ pub async fn allocate(
             unset_file_drop_attr(&repo_path.join(received_present_path), log_target).await;
         }
 
-        if received_since.is_none() {
+        if index.entries.is_empty() && repo_file_paths.is_none() {
+            let untracked_stdout = command_output_quiet(
+                Command::new("git")
+                    .args(["ls-files", "-z", "-o"])
+                    .current_dir(repo_path),
+            )
+            .await
+            .map(|x| x.stdout)
+            .unwrap_or_default();
             for untracked_path in HashSet::<PathBuf>::from_iter(
-                from_utf8(
-                    &Command::new("git")
-                        .args(["ls-files", "-z", "-o"])
-                        .current_dir(repo_path)
-                        .output()
-                        .await
-                        .expect("unable to get file list")
-                        .stdout,
-                )
-                .unwrap()
-                .trim()
-                .split_terminator("\u{0}")
-                .filter(|x| repo_path.join(x).try_exists().unwrap())
-                .map(|x| PathBuf::from(x)),
+                nul_separated_paths(&untracked_stdout).filter(|x| repo_path.join(x).exists()),
             ) {
                 unset_file_drop_attr(&repo_path.join(untracked_path), log_target).await;
             }
         }
 
-        let send_paths = tracked_paths.difference(&received_paths);
-        let send_paths_ct = send_paths.clone().count();
+        let send_paths_ct = send_paths.len();
         log(&format!("files to move ({})", send_paths_ct), log_target).await;
 
         if send_paths_ct > 0 {
             let mut has_tested_available_remotes = false;
 
-            let commit_date = DateTime::parse_from_rfc3339(
-                from_utf8(
-                    &Command::new("git")
-                        .args(["log", "-1", "--format=%aI"])
-                        .current_dir(repo_path)
-                        .output()
-                        .await
-                        .expect("unable to get commit date")
-                        .stdout,
-                )
-                .unwrap()
-                .trim(),
-            )
-            .unwrap();
-            let uncommitted_paths = tracked_paths
-                .clone()
-                .into_iter()
-                .filter(|x| {
-                    DateTime::<chrono::Local>::from(
-                        repo_path.join(x).metadata().unwrap().modified().unwrap(),
-                    ) > commit_date
-                })
-                .collect::<Vec<PathBuf>>();
-
-            for send_path in send_paths {
+            let uncommitted_reasons =
+                git_uncommitted_reasons(repo_path, &send_paths.iter().collect::<Vec<&PathBuf>>())
+                    .await;
+
+            for send_path in &send_paths {
                 if has_file_drop_attr(&repo_path.join(send_path)) {
                     // Was present, now want to be dropped
-                    if !tracked_dropped_paths.contains(send_path) {
+                    if pinned_paths.contains(send_path) {
+                        unset_file_drop_attr(&repo_path.join(send_path), log_target).await;
+                        log(
+                            &format!("revert-drop-attribute {:?}, pinned", send_path.display()),
+                            log_target,
+                        )
+                        .await;
+                    } else if !tracked_dropped_paths.contains(send_path) {
                         if !has_tested_available_remotes {
                             test_available_remotes(repo_path, log_target).await;
                             has_tested_available_remotes = true;
                         }
 
-                        if uncommitted_paths.contains(send_path) {
+                        if let Some(uncommitted_reason) = uncommitted_reasons.get(send_path) {
                             unset_file_drop_attr(&repo_path.join(send_path), log_target).await;
-                            log("revert-drop-attribute, uncommited", log_target).await;
-                        } else {
-                            let is_command_ok = command_output_logfile(
-                                Command::new("git")
-                                    .args(["annex", "drop", &format!("{}", send_path.display())])
-                                    .current_dir(repo_path),
-                                format!("git-annex-drop {:?}", repo_path.display()),
+                            log(
+                                &format!(
+                                    "revert-drop-attribute {}, uncommitted ({})",
+                                    send_path.display(),
+                                    uncommitted_reason
+                                ),
                                 log_target,
                             )
                             .await;
+                        } else {
+                            let is_command_ok = annex_drop(repo_path, send_path, log_target).await;
                             if is_command_ok {
                                 set_file_drop_attr(&repo_path.join(send_path), log_target).await;
+                                tracked_dropped_paths.insert(send_path.clone());
                             } else {
                                 is_repo_ok = false;
                             }
@@ -227,26 +1091,22 @@ pub async fn allocate(
                             has_tested_available_remotes = true;
                         }
 
-                        if uncommitted_paths.contains(send_path) {
+                        if let Some(uncommitted_reason) = uncommitted_reasons.get(send_path) {
                             set_file_drop_attr(&repo_path.join(send_path), log_target).await;
-                            log("revert-drop-attribute, uncommited", log_target).await;
+                            log(
+                                &format!(
+                                    "revert-drop-attribute {}, uncommitted ({})",
+                                    send_path.display(),
+                                    uncommitted_reason
+                                ),
+                                log_target,
+                            )
+                            .await;
                         } else {
-                            let mut is_command_ok: bool = false;
-                            let mut get_ct = 0;
-
-                            while get_ct < GET_MAX_CT && !is_command_ok {
-                                is_command_ok = command_output_logfile(
-                                    Command::new("git")
-                                        .args(["annex", "get", &format!("{}", send_path.display())])
-                                        .current_dir(repo_path),
-                                    format!("git-annex-get {:?}", repo_path.display()),
-                                    log_target,
-                                )
-                                .await;
-                                get_ct += 1;
-                            }
+                            let is_command_ok = annex_get(repo_path, send_path, log_target).await;
                             if is_command_ok {
                                 unset_file_drop_attr(&repo_path.join(send_path), log_target).await;
+                                tracked_dropped_paths.remove(send_path);
                             } else {
                                 is_repo_ok = false;
                             }
@@ -255,6 +1115,78 @@ pub async fn allocate(
                 }
             }
         }
+
+        if repo_file_paths.is_none()
+            && are_pinned_paths_ok
+            && !repo_config.allocate_rules.is_empty()
+            && !allocate_rules(
+                repo_path,
+                &repo_config.allocate_rules,
+                &pinned_paths,
+                &tracked_paths,
+                &tracked_dropped_paths,
+                log_target,
+            )
+            .await
+        {
+            is_repo_ok = false;
+        }
+
+        if let (Some(quota_mb), None, true) = (
+            repo_config.allocate_quota_mb,
+            &repo_file_paths,
+            are_pinned_paths_ok,
+        ) {
+            if !allocate_quota(
+                repo_path,
+                quota_mb,
+                repo_config.allocate_quota_policy.unwrap_or_default(),
+                &pinned_paths,
+                log_target,
+            )
+            .await
+            {
+                is_repo_ok = false;
+            }
+        }
+
+        // Entries moved by rules or quota are left as they were, the next run sees them as moved,
+        // and so are all examined entries when their keys are unknown
+        let examined_paths: Vec<&PathBuf> = received_paths.union(&send_paths).collect();
+        let examined_keys = match examined_paths.is_empty() {
+            true => None,
+            false => annex_keys(repo_path, &examined_paths).await,
+        };
+        if let Some(examined_keys) = examined_keys {
+            for examined_path in examined_paths {
+                match file_times_ns(&repo_path.join(examined_path)) {
+                    Some((mtime_ns, ctime_ns)) => {
+                        index.entries.insert(
+                            examined_path.clone(),
+                            AllocateIndexEntry {
+                                key: examined_keys.get(examined_path).cloned(),
+                                mtime_ns,
+                                ctime_ns,
+                                is_dropped: tracked_dropped_paths.contains(examined_path),
+                            },
+                        );
+                    }
+                    None => {
+                        index.entries.remove(examined_path);
+                    }
+                }
+            }
+        }
+        if repo_file_paths.is_none() {
+            index.entries.retain(|x, _| tracked_paths.contains(x));
+            index.commit = head_commit;
+            index.annex_commit = annex_commit;
+            index.dir_mtimes_ns = dir_mtimes_ns;
+        }
+        if let Err(e) = write_repo_state(repo_path, ALLOCATE_INDEX_NAME, &index) {
+            log(&format!("index not ok ({})", e), log_target).await;
+        }
+
         log(
             &format!(
                 "allocate-repo-files {} {}",
@@ -282,3 +1214,134 @@ pub async fn allocate(
     .await;
     is_ok
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::FileTime;
+    use std::env;
+
+    #[test]
+    fn sort_quota_candidates_follows_policy() {
+        let repo_path = env::temp_dir().join(format!(
+            "git-annex-archiver-test-quota-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&repo_path).unwrap();
+        // small.bin was read last and written first, large.bin the other way around
+        for (file, time_s) in [("small.bin", 100), ("large.bin", 200), ("gone.bin", 0)] {
+            if time_s > 0 {
+                fs::write(repo_path.join(file), file).unwrap();
+                filetime::set_file_times(
+                    repo_path.join(file),
+                    FileTime::from_unix_time(300 - time_s, 0),
+                    FileTime::from_unix_time(time_s, 0),
+                )
+                .unwrap();
+            }
+        }
+        let candidate_files = || -> Vec<AnnexFind> {
+            [
+                ("small.bin", "10"),
+                ("large.bin", "2000"),
+                ("gone.bin", "?"),
+            ]
+            .into_iter()
+            .map(|(file, bytesize)| AnnexFind {
+                file: String::from(file),
+                key: String::new(),
+                bytesize: String::from(bytesize),
+            })
+            .collect()
+        };
+        let sorted_files = |quota_policy| -> Vec<String> {
+            let mut files = candidate_files();
+            sort_quota_candidates(&repo_path, &mut files, quota_policy);
+            files.into_iter().map(|x| x.file).collect()
+        };
+
+        // a file without a time counts as the oldest, one without a size as the smallest
+        assert_eq!(
+            sorted_files(AllocateQuotaPolicy::Accessed),
+            ["gone.bin", "large.bin", "small.bin"]
+        );
+        assert_eq!(
+            sorted_files(AllocateQuotaPolicy::Oldest),
+            ["gone.bin", "small.bin", "large.bin"]
+        );
+        assert_eq!(
+            sorted_files(AllocateQuotaPolicy::Largest),
+            ["large.bin", "small.bin", "gone.bin"]
+        );
+        fs::remove_dir_all(&repo_path).unwrap();
+    }
+
+    #[test]
+    fn parse_cat_file_batch_keeps_request_order() {
+        assert_eq!(
+            parse_cat_file_batch(
+                b"1f2e blob 11\n../.git/a/K\nHEAD:gone missing\n3a4b blob 3\nK\n\n\n"
+            ),
+            Some(vec![
+                Some(b"../.git/a/K".to_vec()),
+                None,
+                Some(b"K\n\n".to_vec()),
+            ])
+        );
+        assert_eq!(parse_cat_file_batch(b"1f2e blob 11\nshort\n"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nul_separated_paths_keeps_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(
+            nul_separated_paths(b"a/b\0c\xff.txt\0").collect::<Vec<PathBuf>>(),
+            vec![
+                PathBuf::from("a/b"),
+                PathBuf::from(OsStr::from_bytes(b"c\xff.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_matching_options_groups_quoted_words() {
+        assert_eq!(
+            split_matching_options("--largerthan=1GB  --not --in=nas"),
+            Some(vec![
+                String::from("--largerthan=1GB"),
+                String::from("--not"),
+                String::from("--in=nas"),
+            ])
+        );
+        assert_eq!(
+            split_matching_options(r#"--metadata "tag=to watch" --include='*.mkv'"#),
+            Some(vec![
+                String::from("--metadata"),
+                String::from("tag=to watch"),
+                String::from("--include=*.mkv"),
+            ])
+        );
+        assert_eq!(
+            split_matching_options(r"--include=a\ b ''"),
+            Some(vec![String::from("--include=a b"), String::new()])
+        );
+        assert_eq!(split_matching_options("--metadata 'tag=open"), None);
+    }
+
+    #[test]
+    fn rule_path_needs_literal_separator() {
+        let pattern = Pattern::new("*.iso").unwrap();
+        assert!(matches_rule_path(&pattern, Path::new("disk.iso")));
+        assert!(!matches_rule_path(&pattern, Path::new("images/disk.iso")));
+
+        let pattern = Pattern::new("images/**/*.iso").unwrap();
+        assert!(matches_rule_path(&pattern, Path::new("images/disk.iso")));
+        assert!(matches_rule_path(
+            &pattern,
+            Path::new("images/old/disk.iso")
+        ));
+        assert!(!matches_rule_path(&pattern, Path::new("videos/disk.iso")));
+    }
+}
@@ -0,0 +1,302 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use tokio::process::Command;
+use tokio::time::Instant;
+
+use crate::config::RepoConfig;
+use crate::format::format_size_text;
+
+use super::unused::{key_size, unix_now_s};
+use super::{command_output_quiet_until, log, read_repo_state, write_repo_state, LogTarget};
+
+pub const COVERAGE_REPORT_NAME: &str = "coverage-report.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CoverageReport {
+    pub generated_s: u64,
+    pub lacking_files: Vec<CoverageFile>,
+    pub single_copy_files: Vec<CoverageFile>,
+    /// Files and bytes held by each location, by its description
+    pub locations: BTreeMap<String, CoverageHolding>,
+    /// Files below numcopies in the report before this one, having more is unhealthy
+    #[serde(default)]
+    pub previous_lacking_ct: Option<u64>,
+    /// Files below numcopies allowed when the report was generated, used instead of the
+    /// previous report when set
+    #[serde(default)]
+    pub lacking_max: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CoverageFile {
+    pub file: String,
+    pub size: Option<u64>,
+    pub locations: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CoverageHolding {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl CoverageReport {
+    pub fn lacking_bytes(&self) -> u64 {
+        self.lacking_files.iter().filter_map(|x| x.size).sum()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        let lacking_ct = self.lacking_files.len() as u64;
+        match self.lacking_max {
+            Some(lacking_max) => lacking_ct <= lacking_max,
+            None => self.previous_lacking_ct.is_none_or(|x| lacking_ct <= x),
+        }
+    }
+
+    pub fn health_text(&self) -> String {
+        match (self.is_healthy(), self.lacking_max) {
+            (true, _) => String::from("ok"),
+            (false, Some(lacking_max)) => format!("unhealthy, over {} allowed", lacking_max),
+            (false, None) => format!(
+                "unhealthy, up from {}",
+                self.previous_lacking_ct.unwrap_or(0)
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WhereisOutput {
+    file: String,
+    key: String,
+    whereis: Vec<WhereisLocation>,
+}
+
+#[derive(Deserialize)]
+struct WhereisLocation {
+    description: String,
+    here: bool,
+}
+
+#[derive(Deserialize)]
+struct FindOutput {
+    file: String,
+}
+
+/// Output lines of a git-annex --json command, None when it failed or was stopped at the deadline
+async fn annex_json_lines<T: DeserializeOwned>(
+    repo_path: &Path,
+    args: &[&str],
+    deadline: Option<Instant>,
+) -> Option<Vec<T>> {
+    let output = command_output_quiet_until(
+        Command::new("git").args(args).current_dir(repo_path),
+        deadline,
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(
+        from_utf8(&output.stdout)
+            .ok()?
+            .lines()
+            .filter_map(|x| serde_json::from_str(x).ok())
+            .collect(),
+    )
+}
+
+/// Checks which files lack copies and where content is held, keeping the result as a report
+pub(crate) async fn numcopies_coverage(
+    repo_path: &Path,
+    repo_config: &RepoConfig,
+    deadline: Option<Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> Option<CoverageReport> {
+    log(
+        &format!("numcopies-coverage {:?}", repo_path.display()),
+        log_target,
+    )
+    .await;
+
+    let (Some(whereis_outputs), Some(find_outputs)) = (
+        annex_json_lines::<WhereisOutput>(repo_path, &["annex", "whereis", "--json"], deadline)
+            .await,
+        annex_json_lines::<FindOutput>(
+            repo_path,
+            &["annex", "find", "--lackingcopies=1", "--json"],
+            deadline,
+        )
+        .await,
+    ) else {
+        log(
+            &format!("numcopies-coverage {:?} not ok", repo_path.display()),
+            log_target,
+        )
+        .await;
+        return None;
+    };
+
+    let mut files: HashMap<String, CoverageFile> = HashMap::new();
+    let previous_report: CoverageReport = read_repo_state(repo_path, COVERAGE_REPORT_NAME);
+    let mut report = CoverageReport {
+        generated_s: unix_now_s(),
+        // the first report has nothing to compare with
+        previous_lacking_ct: (previous_report.generated_s > 0)
+            .then_some(previous_report.lacking_files.len() as u64),
+        lacking_max: repo_config.numcopies_lacking_max,
+        ..Default::default()
+    };
+    for whereis_output in whereis_outputs {
+        let size = key_size(&whereis_output.key);
+        let locations: Vec<String> = whereis_output
+            .whereis
+            .into_iter()
+            .map(|x| match x.here {
+                true => String::from("here"),
+                false => x.description,
+            })
+            .collect();
+        for location in &locations {
+            let holding = report.locations.entry(location.clone()).or_default();
+            holding.files += 1;
+            holding.bytes += size.unwrap_or(0);
+        }
+        files.insert(
+            whereis_output.file.clone(),
+            CoverageFile {
+                file: whereis_output.file,
+                size,
+                locations,
+            },
+        );
+    }
+    for find_output in find_outputs {
+        if let Some(file) = files.get(&find_output.file) {
+            report.lacking_files.push(CoverageFile {
+                file: file.file.clone(),
+                size: file.size,
+                locations: file.locations.clone(),
+            });
+        }
+    }
+    report.single_copy_files = files
+        .into_values()
+        .filter(|x| x.locations.len() <= 1)
+        .collect();
+    report.single_copy_files.sort_by(|a, b| a.file.cmp(&b.file));
+
+    for (location, holding) in &report.locations {
+        log(
+            &format!(
+                "numcopies-location {} {} files, {}",
+                location,
+                holding.files,
+                format_size_text(holding.bytes)
+            ),
+            log_target,
+        )
+        .await;
+    }
+    log(
+        &format!(
+            "numcopies-coverage {:?} {} ({} files, {} below numcopies, {} files in one place only)",
+            repo_path.display(),
+            report.health_text(),
+            report.lacking_files.len(),
+            format_size_text(report.lacking_bytes()),
+            report.single_copy_files.len()
+        ),
+        log_target,
+    )
+    .await;
+    if let Err(e) = write_repo_state(repo_path, COVERAGE_REPORT_NAME, &report) {
+        log(&format!("coverage-report not ok ({})", e), log_target).await;
+    }
+    Some(report)
+}
+
+/// Whether no repository has more files below numcopies than allowed or than before, as of the
+/// latest reports
+pub fn is_coverage_healthy(repo_paths: &[PathBuf]) -> bool {
+    repo_paths
+        .iter()
+        .all(|x| read_repo_state::<CoverageReport>(x, COVERAGE_REPORT_NAME).is_healthy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn coverage_report(
+        lacking_ct: usize,
+        previous_lacking_ct: Option<u64>,
+        lacking_max: Option<u64>,
+    ) -> CoverageReport {
+        CoverageReport {
+            generated_s: 1,
+            lacking_files: (0..lacking_ct)
+                .map(|x| CoverageFile {
+                    file: format!("file-{}", x),
+                    size: Some(1),
+                    locations: vec![String::from("here")],
+                })
+                .collect(),
+            previous_lacking_ct,
+            lacking_max,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn is_healthy_compares_with_previous_report() {
+        assert!(coverage_report(3, None, None).is_healthy());
+        assert!(coverage_report(2, Some(3), None).is_healthy());
+        assert!(coverage_report(3, Some(3), None).is_healthy());
+        assert!(!coverage_report(4, Some(3), None).is_healthy());
+        assert_eq!(
+            coverage_report(4, Some(3), None).health_text(),
+            "unhealthy, up from 3"
+        );
+    }
+
+    #[test]
+    fn is_healthy_allows_up_to_lacking_max() {
+        assert!(coverage_report(0, None, Some(0)).is_healthy());
+        assert!(!coverage_report(1, None, Some(0)).is_healthy());
+        assert!(coverage_report(3, Some(1), Some(3)).is_healthy());
+        assert!(!coverage_report(4, Some(5), Some(3)).is_healthy());
+        assert_eq!(
+            coverage_report(4, Some(5), Some(3)).health_text(),
+            "unhealthy, over 3 allowed"
+        );
+    }
+
+    #[test]
+    fn is_coverage_healthy_needs_every_repository_healthy() {
+        let test_path = env::temp_dir().join(format!(
+            "git-annex-archiver-test-coverage-{}",
+            std::process::id()
+        ));
+        let repo_paths = vec![test_path.join("healthy"), test_path.join("unhealthy")];
+        write_repo_state(
+            &repo_paths[0],
+            COVERAGE_REPORT_NAME,
+            &coverage_report(2, Some(2), None),
+        )
+        .unwrap();
+        write_repo_state(
+            &repo_paths[1],
+            COVERAGE_REPORT_NAME,
+            &coverage_report(1, Some(0), None),
+        )
+        .unwrap();
+
+        assert!(is_coverage_healthy(&repo_paths[..1]));
+        assert!(!is_coverage_healthy(&repo_paths));
+        // a repository without a report yet is not held against the others
+        assert!(is_coverage_healthy(&[test_path.join("new")]));
+        std::fs::remove_dir_all(&test_path).unwrap();
+    }
+}
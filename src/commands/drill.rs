@@ -0,0 +1,268 @@
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+use crate::format::format_size_text;
+
+use super::unused::{key_size, unix_now_s};
+use super::{
+    command_output_logfile, command_output_quiet, lock_repo, log, read_repo_state, repo_state_path,
+    test_available_remotes, write_repo_state, LogTarget,
+};
+
+pub const DRILL_RECORD_NAME: &str = "drill-record.json";
+const DRILL_SCRATCH_NAME: &str = "drill-scratch";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DrillRecord {
+    /// Latest restore results, by remote name
+    pub remotes: HashMap<String, DrillRemoteRecord>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DrillRemoteRecord {
+    pub drilled_s: u64,
+    pub sampled_ct: u64,
+    pub restored_ct: u64,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    /// Counts over every drill of the remote
+    pub total_sampled_ct: u64,
+    pub total_restored_ct: u64,
+}
+
+impl DrillRemoteRecord {
+    pub fn throughput_text(&self) -> String {
+        match self.duration_ms {
+            0 => String::from("? MB/s"),
+            duration_ms => format!("{}/s", format_size_text(self.bytes * 1000 / duration_ms)),
+        }
+    }
+}
+
+/// Clone without content, knowing the remotes of the repository, to restore into
+async fn make_scratch_clone(
+    repo_path: &Path,
+    scratch_path: &Path,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    if !command_output_logfile(
+        Command::new("git")
+            .args(["clone", "--quiet", "--no-checkout"])
+            .args([repo_path, scratch_path]),
+        format!("git-clone-scratch {:?}", scratch_path.display()),
+        log_target,
+    )
+    .await
+    {
+        return false;
+    }
+    if !command_output_logfile(
+        Command::new("git")
+            .args(["annex", "init", "--quiet", "archiver drill"])
+            .current_dir(scratch_path),
+        format!("git-annex-init {:?}", scratch_path.display()),
+        log_target,
+    )
+    .await
+    {
+        return false;
+    }
+
+    // the remotes of the repository replace the origin pointing back to it
+    let is_origin_removed = command_output_quiet(
+        Command::new("git")
+            .args(["remote", "remove", "origin"])
+            .current_dir(scratch_path),
+    )
+    .await
+    .is_some_and(|x| x.status.success());
+    let remote_config_output = command_output_quiet(
+        Command::new("git")
+            .args(["config", "--get-regexp", r"^remote\."])
+            .current_dir(repo_path),
+    )
+    .await
+    // without any remote configured, nothing matches and git config exits with 1
+    .filter(|x| x.status.success() || x.status.code() == Some(1));
+    let (true, Some(remote_config_output)) = (is_origin_removed, remote_config_output) else {
+        log(
+            &format!("git-remote-scratch {:?} not ok", scratch_path.display()),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    for (name, value) in from_utf8(&remote_config_output.stdout)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| x.split_once(' '))
+    {
+        let is_added = command_output_quiet(
+            Command::new("git")
+                .args(["config", "--add", name, value])
+                .current_dir(scratch_path),
+        )
+        .await
+        .is_some_and(|x| x.status.success());
+        if !is_added {
+            log(&format!("git-config-scratch {} not ok", name), log_target).await;
+            return false;
+        }
+    }
+    true
+}
+
+async fn remove_scratch_clone(scratch_path: &Path) {
+    // annexed objects are read-only
+    command_output_quiet(Command::new("chmod").args(["-R", "u+w"]).arg(scratch_path)).await;
+    tokio::fs::remove_dir_all(scratch_path).await.ok();
+}
+
+/// Keys of the files whose content a remote holds
+async fn remote_keys(repo_path: &Path, remote: &str) -> Vec<String> {
+    let mut keys: Vec<String> = command_output_quiet(
+        Command::new("git")
+            .args([
+                "annex",
+                "find",
+                &format!("--in={}", remote),
+                "--format=${key}\n",
+            ])
+            .current_dir(repo_path),
+    )
+    .await
+    .map(|x| {
+        from_utf8(&x.stdout)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    })
+    .unwrap_or_default();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Restores a key from a remote alone, verifies its checksum, then drops it again,
+/// returning how long the transfer took
+async fn drill_key(
+    scratch_path: &Path,
+    remote: &str,
+    key: &str,
+    log_target: &mut LogTarget<'_>,
+) -> Option<Duration> {
+    let start = Instant::now();
+    let is_restored = command_output_logfile(
+        Command::new("git")
+            .args(["annex", "get", &format!("--from={}", remote)])
+            .arg(format!("--key={}", key))
+            .current_dir(scratch_path),
+        format!("git-annex-get {} {}", remote, key),
+        log_target,
+    )
+    .await;
+    let duration = start.elapsed();
+    let is_verified = is_restored
+        && command_output_logfile(
+            Command::new("git")
+                .args(["annex", "fsck", &format!("--key={}", key)])
+                .current_dir(scratch_path),
+            format!("git-annex-fsck {}", key),
+            log_target,
+        )
+        .await;
+    command_output_logfile(
+        Command::new("git")
+            .args(["annex", "drop", "--force", &format!("--key={}", key)])
+            .current_dir(scratch_path),
+        format!("git-annex-drop {}", key),
+        log_target,
+    )
+    .await;
+    match is_verified {
+        true => Some(duration),
+        false => None,
+    }
+}
+
+pub(crate) async fn drill(
+    repo_paths: &[PathBuf],
+    sample_ct: usize,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let mut is_ok = true;
+    for repo_path in repo_paths {
+        log(&format!("drill {:?}", repo_path.display()), log_target).await;
+        let Some(_repo_lock) = lock_repo(repo_path, "drill", log_target).await else {
+            is_ok = false;
+            continue;
+        };
+        // the scratch clone stays with the repository, on the same disk and out of its worktree
+        let scratch_path = repo_state_path(repo_path, DRILL_SCRATCH_NAME);
+        remove_scratch_clone(&scratch_path).await;
+        if !make_scratch_clone(repo_path, &scratch_path, log_target).await {
+            remove_scratch_clone(&scratch_path).await;
+            is_ok = false;
+            continue;
+        }
+
+        let mut record: DrillRecord = read_repo_state(repo_path, DRILL_RECORD_NAME);
+        for remote in test_available_remotes(repo_path, log_target).await {
+            let keys = remote_keys(repo_path, &remote).await;
+            let sampled_keys: Vec<&String> = keys
+                .choose_multiple(&mut rand::thread_rng(), sample_ct)
+                .collect();
+
+            let remote_record = record.remotes.entry(remote.clone()).or_default();
+            remote_record.drilled_s = unix_now_s();
+            remote_record.sampled_ct = sampled_keys.len() as u64;
+            remote_record.restored_ct = 0;
+            remote_record.bytes = 0;
+            remote_record.duration_ms = 0;
+            for key in sampled_keys {
+                if let Some(duration) = drill_key(&scratch_path, &remote, key, log_target).await {
+                    remote_record.restored_ct += 1;
+                    remote_record.bytes += key_size(key).unwrap_or(0);
+                    remote_record.duration_ms += duration.as_millis() as u64;
+                }
+            }
+            remote_record.total_sampled_ct += remote_record.sampled_ct;
+            remote_record.total_restored_ct += remote_record.restored_ct;
+
+            is_ok &= remote_record.restored_ct == remote_record.sampled_ct;
+            log(
+                &format!(
+                    "drill {:?} {} {} of {} restored, {} of {} overall, {}",
+                    repo_path.display(),
+                    remote,
+                    remote_record.restored_ct,
+                    remote_record.sampled_ct,
+                    remote_record.total_restored_ct,
+                    remote_record.total_sampled_ct,
+                    remote_record.throughput_text()
+                ),
+                log_target,
+            )
+            .await;
+        }
+        if let Err(e) = write_repo_state(repo_path, DRILL_RECORD_NAME, &record) {
+            log(&format!("drill-record not ok ({})", e), log_target).await;
+        }
+        remove_scratch_clone(&scratch_path).await;
+    }
+    log(
+        match is_ok {
+            true => "ok",
+            false => "not ok",
+        },
+        log_target,
+    )
+    .await;
+    is_ok
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::str::from_utf8;
+use tokio::process::Command;
+
+use crate::config::RepoConfig;
+
+use super::unused::unix_now_s;
+use super::{command_output_quiet, log, repo_state_path, write_repo_state, LogTarget};
+
+pub const MASS_CHANGE_REPORT_NAME: &str = "mass-change-report.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct MassChangeReport {
+    pub generated_s: u64,
+    pub deleted: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+/// Deleted and modified tracked files, as listed by git status
+async fn pending_changes(repo_path: &Path) -> Option<(Vec<String>, Vec<String>)> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["status", "--porcelain=v1", "-z", "--untracked-files=no"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    parse_pending_changes(from_utf8(&output.stdout).ok()?)
+}
+
+/// Deleted and modified files in the output of git status --porcelain=v1 -z, None when it is
+/// malformed
+fn parse_pending_changes(status: &str) -> Option<(Vec<String>, Vec<String>)> {
+    let mut deleted: Vec<String> = vec![];
+    let mut modified: Vec<String> = vec![];
+    let mut entries = status.split_terminator('\0');
+    while let Some(entry) = entries.next() {
+        let (status, path) = entry.split_at_checked(3)?;
+        if status.contains('R') || status.contains('C') {
+            // the origin of a rename or copy follows as its own entry
+            entries.next();
+        }
+        if status.contains('D') {
+            deleted.push(path.to_string());
+        } else if status.contains(['M', 'T', 'R']) {
+            modified.push(path.to_string());
+        }
+    }
+    Some((deleted, modified))
+}
+
+/// Checks pending changes stay under the configured thresholds before they are committed
+/// and pushed, writing a report of them otherwise
+pub(crate) async fn mass_change_guard(
+    repo_path: &Path,
+    repo_config: &RepoConfig,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let Some((deleted, modified)) = pending_changes(repo_path).await else {
+        log(
+            &format!("mass-change-guard {:?} not ok", repo_path.display()),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+
+    let max_deleted = repo_config.sync_max_deleted.unwrap_or(1000);
+    let max_modified = repo_config.sync_max_modified.unwrap_or(1000);
+    if deleted.len() as u64 <= max_deleted && modified.len() as u64 <= max_modified {
+        fs::remove_file(repo_state_path(repo_path, MASS_CHANGE_REPORT_NAME)).ok();
+        return true;
+    }
+
+    log(
+        &format!(
+            "mass-change-guard {:?} not ok ({} deleted of {} allowed, {} modified of {} allowed)",
+            repo_path.display(),
+            deleted.len(),
+            max_deleted,
+            modified.len(),
+            max_modified
+        ),
+        log_target,
+    )
+    .await;
+    let report = MassChangeReport {
+        generated_s: unix_now_s(),
+        deleted,
+        modified,
+    };
+    if let Err(e) = write_repo_state(repo_path, MASS_CHANGE_REPORT_NAME, &report) {
+        log(&format!("mass-change-report not ok ({})", e), log_target).await;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pending_changes_sorts_deleted_and_modified() {
+        assert_eq!(
+            parse_pending_changes(
+                " D gone.txt\0M  staged.txt\0 M edited.txt\0 T link\0A  new.txt\0"
+            ),
+            Some((
+                vec![String::from("gone.txt")],
+                vec![
+                    String::from("staged.txt"),
+                    String::from("edited.txt"),
+                    String::from("link")
+                ]
+            ))
+        );
+        assert_eq!(parse_pending_changes(""), Some((vec![], vec![])));
+    }
+
+    #[test]
+    fn parse_pending_changes_skips_rename_origins() {
+        assert_eq!(
+            parse_pending_changes("R  new name.txt\0old name.txt\0C  copy.txt\0orig.txt\0D  x\0"),
+            Some((vec![String::from("x")], vec![String::from("new name.txt")]))
+        );
+        // the origin of a renamed and then deleted file is not taken for an entry
+        assert_eq!(
+            parse_pending_changes("RD b\0a\0"),
+            Some((vec![String::from("b")], vec![]))
+        );
+    }
+
+    #[test]
+    fn parse_pending_changes_rejects_malformed() {
+        assert_eq!(parse_pending_changes("M\0"), None);
+    }
+}
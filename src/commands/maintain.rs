@@ -1,188 +1,749 @@
-use glob::glob;
-use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use std::time::Duration;
 use tokio::process::Command;
+use tokio::time::Instant;
 
-use super::{command_output_logfile, log, test_available_remotes, LogTarget};
+use crate::config::{Config, FsckScope, GcMode, RepoConfig};
+use crate::format::{format_size_text, format_timestamp_text};
 
-async fn untrack_embedded_git(search_path: &PathBuf, log_target: &mut LogTarget<'_>) {
+use super::coverage::numcopies_coverage;
+use super::unused::{
+    annex_unused, drop_unused, unix_now_s, update_unused_report_remaining, write_unused_report,
+    UnusedRecord, UNUSED_RECORD_NAME,
+};
+use super::{
+    command_output_logfile_until, command_output_quiet, command_output_status_until,
+    find_embedded_gits, lock_repo_until, log, read_repo_state, test_available_remotes,
+    write_repo_state, LogTarget,
+};
+
+pub(crate) async fn untrack_embedded_git(
+    search_path: &PathBuf,
+    repo_config: &RepoConfig,
+    dry_run: bool,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
     log(
         &format!("untracked-embedded-git {}", search_path.display()),
         log_target,
     )
     .await;
 
-    for entry in glob(search_path.join(Path::new("**/.git")).to_str().unwrap())
-        .expect("unable to read glob pattern")
-        .filter_map(Result::ok)
-    {
-        if !entry.parent().unwrap().eq(search_path) {
-            let _ = Command::new("git")
-                .args([
-                    "rm",
-                    "-r",
-                    "--cached",
-                    entry
-                        .as_os_str()
-                        .to_str()
-                        .unwrap()
-                        .strip_suffix(".git")
-                        .unwrap(),
-                ])
-                .current_dir(search_path)
-                .output()
-                .await;
-            log(&format!("git-rm-cached {} ok", entry.display()), log_target).await;
+    let Some(embedded_git_paths) = find_embedded_gits(search_path, repo_config, log_target).await
+    else {
+        log(
+            &format!("untracked-embedded-git {} not ok", search_path.display()),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    let mut is_ok = true;
+    for entry in embedded_git_paths {
+        let Some(output) = command_output_quiet(
+            Command::new("git")
+                .args(
+                    [
+                        "rm",
+                        "-r",
+                        "--cached",
+                        "--ignore-unmatch",
+                        if dry_run { "--dry-run" } else { "" },
+                        entry
+                            .as_os_str()
+                            .to_str()
+                            .unwrap()
+                            .strip_suffix(".git")
+                            .unwrap(),
+                    ]
+                    .into_iter()
+                    .filter(|arg| !arg.is_empty()),
+                )
+                .current_dir(search_path),
+        )
+        .await
+        else {
+            log(
+                &format!("git-rm-cached {} not ok", entry.display()),
+                log_target,
+            )
+            .await;
+            is_ok = false;
+            continue;
+        };
+
+        let removed_paths: Vec<&str> = from_utf8(&output.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|x| x.strip_prefix("rm '")?.strip_suffix('\''))
+            .collect();
+        if dry_run {
+            for removed_path in &removed_paths {
+                log(&format!("would-rm-cached {}", removed_path), log_target).await;
+            }
         }
+        log(
+            &format!(
+                "git-rm-cached {} {} ({} files)",
+                entry.display(),
+                match (output.status.success(), dry_run) {
+                    (true, true) => String::from("dry-run"),
+                    (true, false) => String::from("ok"),
+                    (false, _) => format!(
+                        "not ok, {}",
+                        from_utf8(&output.stderr).unwrap_or_default().trim()
+                    ),
+                },
+                removed_paths.len()
+            ),
+            log_target,
+        )
+        .await;
+        is_ok &= output.status.success();
     }
     log(
-        &format!("untracked-embedded-git {} ok", search_path.display()),
+        &format!(
+            "untracked-embedded-git {} {}",
+            search_path.display(),
+            match is_ok {
+                true => "ok",
+                false => "not ok",
+            }
+        ),
         log_target,
     )
     .await;
+    is_ok
+}
+
+const MAINTAIN_CURSOR_NAME: &str = "maintain-cursor.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct MaintainCursor {
+    /// Last time each remote, or "here", was fsck'ed and had unused content handled
+    completed_s: HashMap<String, u64>,
+}
+
+/// Remotes maintained in a repository, plus "here"
+async fn maintain_locations(repo_path: &Path, repo_config: &RepoConfig) -> Vec<String> {
+    let mut locations: Vec<String> =
+        command_output_quiet(Command::new("git").args(["remote"]).current_dir(repo_path))
+            .await
+            .map(|x| {
+                from_utf8(&x.stdout)
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+    locations.push(String::from("here"));
+    locations.retain(|x| {
+        !repo_config
+            .fsck_config(Some(x.as_str()).filter(|x| *x != "here"))
+            .excluded
+            .unwrap_or(false)
+    });
+    locations
+}
+
+async fn maintain_coverage(
+    repo_paths: &Vec<PathBuf>,
+    config: &Config,
+    run_start_s: u64,
+    log_target: &mut LogTarget<'_>,
+) {
+    let now_s = unix_now_s();
+    let mut location_ct = 0;
+    let mut completed_ct = 0;
+    for repo_path in repo_paths {
+        let cursor: MaintainCursor = read_repo_state(repo_path, MAINTAIN_CURSOR_NAME);
+        for location in maintain_locations(repo_path, &config.repo_config(repo_path)).await {
+            let completed_s = cursor.completed_s.get(&location).copied();
+            location_ct += 1;
+            if completed_s.is_some_and(|x| x >= run_start_s) {
+                completed_ct += 1;
+            }
+            if let Some(sla_d) = config.maintain_sla_d {
+                if completed_s.is_none_or(|x| now_s - x > sla_d * 86400) {
+                    log(
+                        &format!(
+                            "maintain-overdue {} {} (last {})",
+                            repo_path.display(),
+                            location,
+                            completed_s
+                                .map(format_timestamp_text)
+                                .unwrap_or(String::from("never"))
+                        ),
+                        log_target,
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+    log(
+        &format!("maintain-coverage {} of {}", completed_ct, location_ct),
+        log_target,
+    )
+    .await;
+}
+
+/// Size of loose and packed objects, as reported by git count-objects
+async fn git_objects_size_kib(repo_path: &Path) -> Option<u64> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["count-objects", "-v"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    from_utf8(&output.stdout)
+        .ok()?
+        .lines()
+        .filter_map(|x| x.split_once(": "))
+        .filter(|(name, _)| ["size", "size-pack", "size-garbage"].contains(name))
+        .map(|(_, value)| value.trim().parse::<u64>().ok())
+        .sum()
+}
+
+/// Seconds in a git-annex duration such as 1d12h or 90m
+fn parse_annex_duration_s(duration: &str) -> Option<u64> {
+    let mut total_s = 0;
+    let mut number = String::new();
+    for c in duration.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            _ => {
+                let unit_s = match c {
+                    'y' => 365 * 86400,
+                    'd' => 86400,
+                    'h' => 3600,
+                    'm' => 60,
+                    's' => 1,
+                    _ => return None,
+                };
+                total_s += number.parse::<u64>().ok()? * unit_s;
+                number.clear();
+            }
+        }
+    }
+    match number.is_empty() && !duration.is_empty() {
+        true => Some(total_s),
+        false => None,
+    }
+}
+
+/// Steps preparing a repository before its locations are checked
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PreparationStep {
+    Untrack,
+    GitFsck,
+    /// Only run when git fsck is not ok
+    Repair,
+    Unused,
+    Restage,
+    Gc(GcMode),
+    Forget,
+}
+
+impl PreparationStep {
+    fn name(&self) -> &'static str {
+        match self {
+            PreparationStep::Untrack => "untrack",
+            PreparationStep::GitFsck => "git-fsck",
+            PreparationStep::Repair => "repair",
+            PreparationStep::Unused => "unused",
+            PreparationStep::Restage => "restage",
+            PreparationStep::Gc(_) => "gc",
+            PreparationStep::Forget => "forget",
+        }
+    }
+}
+
+/// Preparation steps the repository config asks for, in the order they run
+fn preparation_steps(repo_config: &RepoConfig) -> Vec<PreparationStep> {
+    [
+        Some(PreparationStep::Untrack),
+        Some(PreparationStep::GitFsck),
+        repo_config
+            .maintain_repair
+            .unwrap_or(false)
+            .then_some(PreparationStep::Repair),
+        Some(PreparationStep::Unused),
+        Some(PreparationStep::Restage),
+        repo_config.maintain_gc.map(PreparationStep::Gc),
+        repo_config
+            .maintain_forget_dead
+            .unwrap_or(false)
+            .then_some(PreparationStep::Forget),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// How a step of a maintain run ended
+#[derive(Debug, PartialEq)]
+enum StepOutcome {
+    Completed,
+    /// Not ok on its own, before the deadline came
+    Failed,
+    /// Stopped by the deadline, taken up again by the next run
+    Interrupted,
+}
+
+fn step_outcome(is_step_ok: bool, end: Instant, deadline: Instant) -> StepOutcome {
+    match (is_step_ok, end >= deadline) {
+        (true, _) => StepOutcome::Completed,
+        (false, false) => StepOutcome::Failed,
+        (false, true) => StepOutcome::Interrupted,
+    }
 }
 
 pub(crate) async fn maintain(
     repo_paths: &Vec<PathBuf>,
     timeout_m: u64,
+    config: &Config,
     log_targets: (&mut LogTarget<'_>, &mut LogTarget<'_>),
     notify_progress: impl Fn(String),
 ) -> Result<bool, ()> {
-    let (log_target, log_target_sync) = log_targets;
-    if let Err(_e) = tokio::time::timeout(
-        std::time::Duration::from_secs(timeout_m * 60),
-        async move {
-            let mut shuffled_repo_paths = repo_paths.clone();
-            shuffled_repo_paths.shuffle(&mut rand::thread_rng());
-
-            for (repo_index, repo_path) in repo_paths.iter().enumerate() {
-                notify_progress(format!(
-                    "Preparation, {} of {}",
-                    repo_index + 1,
-                    repo_paths.len()
-                ));
-                untrack_embedded_git(repo_path, log_target).await;
+    // the running step may overrun the deadline by this much before it is stopped
+    const DEADLINE_GRACE_S: u64 = 10 * 60;
+    const FSCK_TIME_LIMIT_CODE: i32 = 101;
 
-                command_output_logfile(
-                    Command::new("git").args(["fsck"]).current_dir(repo_path),
-                    format!("git-fsck {:?}", repo_path.display()),
-                    log_target,
-                )
-                .await;
+    let (log_target, log_target_sync) = log_targets;
+    let run_start_s = unix_now_s();
+    let deadline = Instant::now() + Duration::from_secs(timeout_m * 60);
+    let step_deadline = Some(deadline + Duration::from_secs(DEADLINE_GRACE_S));
+    let mut is_ok = true;
+    let mut completed: Vec<String> = vec![];
+    let mut failed: Vec<String> = vec![];
+    let mut skipped: Vec<String> = vec![];
+    let mut interrupted: Vec<String> = vec![];
 
-                command_output_logfile(
-                    Command::new("git")
-                        .args(["annex", "unused"])
-                        .current_dir(repo_path),
-                    format!("git-annex-unused {:?}", repo_path.display()),
-                    log_target,
-                )
-                .await;
+    // least recently maintained first, so every pair gets its turn across runs
+    let mut ordered_repo_paths: Vec<(u64, &PathBuf)> = vec![];
+    for repo_path in repo_paths {
+        let cursor: MaintainCursor = read_repo_state(repo_path, MAINTAIN_CURSOR_NAME);
+        let locations = maintain_locations(repo_path, &config.repo_config(repo_path)).await;
+        ordered_repo_paths.push((
+            locations
+                .iter()
+                .map(|x| cursor.completed_s.get(x).copied().unwrap_or(0))
+                .min()
+                .unwrap_or(0),
+            repo_path,
+        ));
+    }
+    ordered_repo_paths.sort_by_key(|(completed_s, _)| *completed_s);
 
-                command_output_logfile(
-                    Command::new("git")
-                        .args(["annex", "restage"])
-                        .current_dir(repo_path),
-                    format!("git-annex-restage {:?}", repo_path.display()),
-                    log_target,
-                )
-                .await;
+    for (repo_index, (_, repo_path)) in ordered_repo_paths.iter().enumerate() {
+        let repo_config = config.repo_config(repo_path);
+        let steps = preparation_steps(&repo_config);
+        if Instant::now() >= deadline {
+            for step in steps {
+                skipped.push(format!(
+                    "{} preparation {}",
+                    repo_path.display(),
+                    step.name()
+                ));
             }
+            continue;
+        }
+        notify_progress(format!(
+            "Preparation, {} of {}",
+            repo_index + 1,
+            repo_paths.len()
+        ));
+        let Some(_repo_lock) =
+            lock_repo_until(repo_path, "maintain", Some(deadline), log_target).await
+        else {
+            for step in steps {
+                skipped.push(format!(
+                    "{} preparation {}",
+                    repo_path.display(),
+                    step.name()
+                ));
+            }
+            continue;
+        };
 
-            for (repo_index, repo_path) in shuffled_repo_paths.iter().enumerate() {
-                notify_progress(format!("{}/{}", repo_index + 1, repo_paths.len()));
-                let available_remotes = test_available_remotes(repo_path, log_target).await;
-
-                command_output_logfile(
-                    Command::new("git")
-                        .args(
-                            [
-                                vec!["annex", "satisfy", "--all"]
-                                    .into_iter()
-                                    .filter(|arg| !arg.is_empty())
-                                    .collect::<Vec<&str>>(),
-                                available_remotes
-                                    .iter()
-                                    .map(|remote| remote.as_str())
-                                    .collect(),
-                            ]
-                            .concat(),
-                        )
-                        .current_dir(repo_path),
-                    format!("git-annex-satisfy {:?}", repo_path.display()),
-                    log_target,
-                )
-                .await;
-
-                let mut remotes: Vec<Option<&str>> = available_remotes
-                    .iter()
-                    .map(|remote| Some(remote.as_str()))
-                    .collect();
-                remotes.push(None);
-                remotes.shuffle(&mut rand::thread_rng());
-
-                for remote in remotes {
-                    let remote_arg = match remote {
-                        Some(remote_id) => format!("--from={}", remote_id),
-                        None => "".to_string(),
+        let mut is_git_fsck_ok = true;
+        for step in steps {
+            if step == PreparationStep::Repair && is_git_fsck_ok {
+                continue;
+            }
+            let step_display = format!("{} preparation {}", repo_path.display(), step.name());
+            if Instant::now() >= deadline {
+                skipped.push(step_display);
+                continue;
+            }
+            let is_step_ok = match step {
+                PreparationStep::Untrack => {
+                    untrack_embedded_git(repo_path, &repo_config, false, log_target).await
+                }
+                PreparationStep::GitFsck => {
+                    is_git_fsck_ok = command_output_logfile_until(
+                        Command::new("git").args(["fsck"]).current_dir(repo_path),
+                        format!("git-fsck {:?}", repo_path.display()),
+                        step_deadline,
+                        log_target,
+                    )
+                    .await;
+                    is_git_fsck_ok
+                }
+                PreparationStep::Repair => {
+                    command_output_logfile_until(
+                        Command::new("git")
+                            .args(["annex", "repair"])
+                            .current_dir(repo_path),
+                        format!("git-annex-repair {:?}", repo_path.display()),
+                        step_deadline,
+                        log_target,
+                    )
+                    .await
+                }
+                PreparationStep::Unused => {
+                    match annex_unused(repo_path, None, step_deadline, log_target).await {
+                        Some(unused_keys) => {
+                            write_unused_report(repo_path, &unused_keys, step_deadline, log_target)
+                                .await
+                        }
+                        None => false,
+                    }
+                }
+                PreparationStep::Restage => {
+                    command_output_logfile_until(
+                        Command::new("git")
+                            .args(["annex", "restage"])
+                            .current_dir(repo_path),
+                        format!("git-annex-restage {:?}", repo_path.display()),
+                        step_deadline,
+                        log_target,
+                    )
+                    .await
+                }
+                PreparationStep::Gc(gc_mode) => {
+                    let size_before_kib = git_objects_size_kib(repo_path).await;
+                    let status_prefix = match gc_mode {
+                        GcMode::Gc => format!("git-gc {:?}", repo_path.display()),
+                        GcMode::Repack => format!("git-repack {:?}", repo_path.display()),
                     };
-                    command_output_logfile(
+                    let is_gc_ok = command_output_logfile_until(
                         Command::new("git")
-                            .args(
-                                [
-                                    "annex",
-                                    "fsck",
-                                    "--incremental-schedule=15d",
-                                    "--time-limit=2h",
-                                    "--all",
-                                    &remote_arg,
-                                ]
-                                .into_iter()
-                                .filter(|arg| !arg.is_empty())
-                                .collect::<Vec<&str>>(),
-                            )
+                            .args(match gc_mode {
+                                GcMode::Gc => vec!["gc", "--quiet"],
+                                GcMode::Repack => vec!["repack", "-a", "-d", "--quiet"],
+                            })
                             .current_dir(repo_path),
-                        format!(
-                            "git-annex-fsck {:?} {}",
-                            repo_path.display(),
-                            match remote {
-                                Some(remote_id) => remote_id,
-                                None => "here",
-                            }
+                        status_prefix.clone(),
+                        step_deadline,
+                        log_target,
+                    )
+                    .await;
+                    let size_after_kib = git_objects_size_kib(repo_path).await;
+                    log(
+                        &format!(
+                            "{} size {} -> {}",
+                            status_prefix,
+                            size_before_kib
+                                .map(|x| format_size_text(x * 1024))
+                                .unwrap_or(String::from("?")),
+                            size_after_kib
+                                .map(|x| format_size_text(x * 1024))
+                                .unwrap_or(String::from("?"))
                         ),
                         log_target,
                     )
                     .await;
-
-                    command_output_logfile(
+                    is_gc_ok
+                }
+                PreparationStep::Forget => {
+                    command_output_logfile_until(
                         Command::new("git")
-                            .args(
-                                ["annex", "dropunused", "all", &remote_arg]
-                                    .into_iter()
-                                    .filter(|arg| !arg.is_empty())
-                                    .collect::<Vec<&str>>(),
-                            )
+                            .args(["annex", "forget", "--drop-dead", "--force"])
                             .current_dir(repo_path),
-                        format!(
-                            "git-annex-dropunused {:?} {}",
-                            repo_path.display(),
-                            match remote {
-                                Some(remote_id) => remote_id,
-                                None => "here",
-                            }
-                        ),
+                        format!("git-annex-forget {:?}", repo_path.display()),
+                        step_deadline,
                         log_target,
                     )
-                    .await;
+                    .await
+                }
+            };
+            match step_outcome(is_step_ok, Instant::now(), deadline) {
+                StepOutcome::Completed => completed.push(step_display),
+                StepOutcome::Failed => {
+                    is_ok = false;
+                    failed.push(step_display);
                 }
+                StepOutcome::Interrupted => interrupted.push(step_display),
             }
-            log("ok", log_target).await;
-        },
-    )
-    .await
-    {
+        }
+    }
+
+    for (repo_index, (_, repo_path)) in ordered_repo_paths.into_iter().enumerate() {
+        let repo_config = config.repo_config(repo_path);
+        if Instant::now() >= deadline {
+            for location in maintain_locations(repo_path, &repo_config).await {
+                skipped.push(format!("{} {}", repo_path.display(), location));
+            }
+            continue;
+        }
+        notify_progress(format!("{}/{}", repo_index + 1, repo_paths.len()));
+        let Some(_repo_lock) =
+            lock_repo_until(repo_path, "maintain", Some(deadline), log_target).await
+        else {
+            for location in maintain_locations(repo_path, &repo_config).await {
+                skipped.push(format!("{} {}", repo_path.display(), location));
+            }
+            continue;
+        };
+        let mut cursor: MaintainCursor = read_repo_state(repo_path, MAINTAIN_CURSOR_NAME);
+        let available_remotes = test_available_remotes(repo_path, log_target).await;
+
+        command_output_logfile_until(
+            Command::new("git")
+                .args(
+                    [
+                        vec!["annex", "satisfy", "--all"]
+                            .into_iter()
+                            .filter(|arg| !arg.is_empty())
+                            .collect::<Vec<&str>>(),
+                        available_remotes
+                            .iter()
+                            .map(|remote| remote.as_str())
+                            .collect(),
+                    ]
+                    .concat(),
+                )
+                .current_dir(repo_path),
+            format!("git-annex-satisfy {:?}", repo_path.display()),
+            step_deadline,
+            log_target,
+        )
+        .await;
+
+        let mut remotes: Vec<Option<&str>> = available_remotes
+            .iter()
+            .map(|remote| Some(remote.as_str()))
+            .collect();
+        remotes.push(None);
+        remotes.sort_by_key(|remote| {
+            cursor
+                .completed_s
+                .get(remote.unwrap_or("here"))
+                .copied()
+                .unwrap_or(0)
+        });
+
+        let mut unused_record: UnusedRecord = read_repo_state(repo_path, UNUSED_RECORD_NAME);
+        let mut dropped_bytes: u64 = 0;
+
+        for remote in remotes {
+            let location_display = format!("{} {}", repo_path.display(), remote.unwrap_or("here"));
+            let fsck_config = repo_config.fsck_config(remote);
+            if fsck_config.excluded.unwrap_or(false) {
+                log(
+                    &format!(
+                        "git-annex-fsck {:?} {} excluded",
+                        repo_path.display(),
+                        remote.unwrap_or("here")
+                    ),
+                    log_target,
+                )
+                .await;
+                continue;
+            }
+            if Instant::now() >= deadline {
+                skipped.push(location_display);
+                continue;
+            }
+
+            let remote_arg = match remote {
+                Some(remote_id) => format!("--from={}", remote_id),
+                None => "".to_string(),
+            };
+            let incremental_schedule_arg = format!(
+                "--incremental-schedule={}",
+                fsck_config.incremental_schedule.as_deref().unwrap_or("15d")
+            );
+            // fsck stops by itself when the deadline comes, keeping its incremental progress
+            let time_limit = fsck_config.time_limit.as_deref().unwrap_or("2h");
+            let time_limit_arg = match parse_annex_duration_s(time_limit) {
+                Some(time_limit_s) => format!(
+                    "--time-limit={}s",
+                    time_limit_s.min(
+                        deadline
+                            .saturating_duration_since(Instant::now())
+                            .as_secs()
+                            .max(1)
+                    )
+                ),
+                None => format!("--time-limit={}", time_limit),
+            };
+            let fsck_status = command_output_status_until(
+                Command::new("git")
+                    .args(
+                        [
+                            "annex",
+                            "fsck",
+                            &incremental_schedule_arg,
+                            &time_limit_arg,
+                            match fsck_config.scope.unwrap_or_default() {
+                                FsckScope::All => "--all",
+                                FsckScope::Worktree => "",
+                            },
+                            &remote_arg,
+                        ]
+                        .into_iter()
+                        .filter(|arg| !arg.is_empty())
+                        .collect::<Vec<&str>>(),
+                    )
+                    .current_dir(repo_path),
+                format!(
+                    "git-annex-fsck {:?} {}",
+                    repo_path.display(),
+                    remote.unwrap_or("here")
+                ),
+                step_deadline,
+                log_target,
+            )
+            .await;
+            // the time limit stops fsck with its own exit code, the next run resumes from there
+            let is_fsck_ok = fsck_status.is_some_and(|x| x.success());
+            let fsck_outcome = match fsck_status.and_then(|x| x.code()) {
+                Some(FSCK_TIME_LIMIT_CODE) => StepOutcome::Interrupted,
+                _ => step_outcome(is_fsck_ok, Instant::now(), deadline),
+            };
+            match fsck_outcome {
+                StepOutcome::Interrupted => {
+                    interrupted.push(location_display);
+                    continue;
+                }
+                StepOutcome::Failed => {
+                    is_ok = false;
+                    failed.push(location_display.clone());
+                }
+                StepOutcome::Completed => (),
+            }
+
+            is_ok &= drop_unused(
+                repo_path,
+                remote,
+                &repo_config,
+                &mut unused_record,
+                &mut dropped_bytes,
+                step_deadline,
+                log_target,
+            )
+            .await;
+            if let Err(e) = write_repo_state(repo_path, UNUSED_RECORD_NAME, &unused_record) {
+                log(&format!("unused-record not ok ({})", e), log_target).await;
+            }
+
+            if is_fsck_ok {
+                cursor
+                    .completed_s
+                    .insert(remote.unwrap_or("here").to_string(), unix_now_s());
+                if let Err(e) = write_repo_state(repo_path, MAINTAIN_CURSOR_NAME, &cursor) {
+                    log(&format!("maintain-cursor not ok ({})", e), log_target).await;
+                }
+                completed.push(location_display);
+            }
+        }
+        if Instant::now() < deadline {
+            update_unused_report_remaining(repo_path, step_deadline, log_target).await;
+            numcopies_coverage(repo_path, &repo_config, step_deadline, log_target).await;
+        }
+    }
+
+    for (status, locations) in [
+        ("completed", &completed),
+        ("failed", &failed),
+        ("skipped", &skipped),
+        ("interrupted", &interrupted),
+    ] {
+        for location in locations {
+            log(&format!("maintain-{} {}", status, location), log_target).await;
+        }
+    }
+    maintain_coverage(repo_paths, config, run_start_s, log_target).await;
+    if !is_ok || !skipped.is_empty() || !interrupted.is_empty() {
         log("not ok", log_target_sync).await;
         return Ok(false);
     }
+    log("ok", log_target).await;
     Ok(true)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_annex_duration_s_adds_units() {
+        assert_eq!(parse_annex_duration_s("90m"), Some(5400));
+        assert_eq!(parse_annex_duration_s("1d12h"), Some(129600));
+        assert_eq!(parse_annex_duration_s("1y2d3h4m5s"), Some(31719845));
+    }
+
+    #[test]
+    fn preparation_steps_follow_repo_config() {
+        assert_eq!(
+            preparation_steps(&RepoConfig::default()),
+            vec![
+                PreparationStep::Untrack,
+                PreparationStep::GitFsck,
+                PreparationStep::Unused,
+                PreparationStep::Restage,
+            ]
+        );
+        let repo_config = RepoConfig {
+            maintain_repair: Some(true),
+            maintain_gc: Some(GcMode::Repack),
+            maintain_forget_dead: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            preparation_steps(&repo_config),
+            vec![
+                PreparationStep::Untrack,
+                PreparationStep::GitFsck,
+                PreparationStep::Repair,
+                PreparationStep::Unused,
+                PreparationStep::Restage,
+                PreparationStep::Gc(GcMode::Repack),
+                PreparationStep::Forget,
+            ]
+        );
+    }
+
+    #[test]
+    fn step_outcome_fails_before_deadline_only() {
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        let before = deadline - Duration::from_secs(60);
+        assert_eq!(step_outcome(true, before, deadline), StepOutcome::Completed);
+        assert_eq!(step_outcome(false, before, deadline), StepOutcome::Failed);
+        assert_eq!(
+            step_outcome(true, deadline, deadline),
+            StepOutcome::Completed
+        );
+        assert_eq!(
+            step_outcome(false, deadline, deadline),
+            StepOutcome::Interrupted
+        );
+    }
+
+    #[test]
+    fn parse_annex_duration_s_rejects_malformed() {
+        assert_eq!(parse_annex_duration_s(""), None);
+        assert_eq!(parse_annex_duration_s("2"), None);
+        assert_eq!(parse_annex_duration_s("h"), None);
+        assert_eq!(parse_annex_duration_s("3w"), None);
+        assert_eq!(parse_annex_duration_s("1h30"), None);
+    }
+}
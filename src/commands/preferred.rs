@@ -0,0 +1,149 @@
+use std::path::Path;
+use std::str::from_utf8;
+use tokio::process::Command;
+
+use crate::config::RepoConfig;
+
+use super::{command_output_quiet, log, LogTarget};
+
+/// Value of a git-annex setting, None when it could not be read
+async fn annex_setting(repo_path: &Path, args: &[&str]) -> Option<String> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .arg("annex")
+            .args(args)
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(from_utf8(&output.stdout).ok()?.trim().to_string())
+}
+
+/// Sets a git-annex setting when it differs from the configured value, logging the correction
+async fn reconcile_setting(
+    repo_path: &Path,
+    args: &[&str],
+    value: &str,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let current_value = annex_setting(repo_path, args).await;
+    if current_value.as_deref() == Some(value) {
+        return true;
+    }
+
+    let is_ok = annex_setting(repo_path, &[args, &[value]].concat())
+        .await
+        .is_some();
+    log(
+        &format!(
+            "git-annex-{} {} ({:?} -> {:?})",
+            args.join(" "),
+            match is_ok {
+                true => "corrected",
+                false => "not ok",
+            },
+            current_value.unwrap_or_default(),
+            value
+        ),
+        log_target,
+    )
+    .await;
+    is_ok
+}
+
+/// Applies the configured preferred content, groups and numcopies, correcting any drift
+pub(crate) async fn reconcile_preferred(
+    repo_path: &Path,
+    repo_config: &RepoConfig,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    if repo_config.preferred.is_empty()
+        && repo_config.numcopies.is_none()
+        && repo_config.mincopies.is_none()
+    {
+        return true;
+    }
+    log(
+        &format!("reconcile-preferred {:?}", repo_path.display()),
+        log_target,
+    )
+    .await;
+
+    let mut is_ok = true;
+    for (setting, copies) in [
+        ("numcopies", repo_config.numcopies),
+        ("mincopies", repo_config.mincopies),
+    ] {
+        if let Some(copies) = copies {
+            is_ok &=
+                reconcile_setting(repo_path, &[setting], &copies.to_string(), log_target).await;
+        }
+    }
+
+    let mut locations: Vec<_> = repo_config.preferred.iter().collect();
+    locations.sort_by_key(|(location, _)| *location);
+    for (location, preferred_config) in locations {
+        for (setting, expression) in [
+            ("wanted", &preferred_config.wanted),
+            ("required", &preferred_config.required),
+        ] {
+            if let Some(expression) = expression {
+                is_ok &= reconcile_setting(repo_path, &[setting, location], expression, log_target)
+                    .await;
+            }
+        }
+
+        if let Some(groups) = &preferred_config.groups {
+            let current_groups: Vec<String> = annex_setting(repo_path, &["group", location])
+                .await
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            for (setting, group) in groups
+                .iter()
+                .filter(|x| !current_groups.contains(x))
+                .map(|x| ("group", x))
+                .chain(
+                    current_groups
+                        .iter()
+                        .filter(|x| !groups.contains(x))
+                        .map(|x| ("ungroup", x)),
+                )
+            {
+                let is_group_ok = annex_setting(repo_path, &[setting, location, group])
+                    .await
+                    .is_some();
+                log(
+                    &format!(
+                        "git-annex-{} {} {} {}",
+                        setting,
+                        location,
+                        group,
+                        match is_group_ok {
+                            true => "corrected",
+                            false => "not ok",
+                        }
+                    ),
+                    log_target,
+                )
+                .await;
+                is_ok &= is_group_ok;
+            }
+        }
+    }
+
+    log(
+        &format!(
+            "reconcile-preferred {:?} {}",
+            repo_path.display(),
+            match is_ok {
+                true => "ok",
+                false => "not ok",
+            }
+        ),
+        log_target,
+    )
+    .await;
+    is_ok
+}
@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+
+use crate::format::{format_size_text, format_timestamp_text};
+
+use super::coverage::{CoverageReport, COVERAGE_REPORT_NAME};
+use super::drill::{DrillRecord, DRILL_RECORD_NAME};
+use super::guard::{MassChangeReport, MASS_CHANGE_REPORT_NAME};
+use super::unused::{UnusedReport, UNUSED_REPORT_NAME};
+use super::{log, read_repo_state, LogTarget};
+
+async fn unused_status(repo_path: &Path, log_target: &mut LogTarget<'_>) {
+    let report: UnusedReport = read_repo_state(repo_path, UNUSED_REPORT_NAME);
+    if report.generated_s == 0 {
+        log("unused: no report, run maintain first", log_target).await;
+        return;
+    }
+
+    log(
+        &format!(
+            "unused: {} keys, {} ({})",
+            report.entries.len(),
+            format_size_text(report.entries.iter().filter_map(|x| x.size).sum()),
+            format_timestamp_text(report.generated_s)
+        ),
+        log_target,
+    )
+    .await;
+    for entry in &report.entries {
+        log(
+            &format!(
+                "  {} {}, since {}, files: {}, in: {}",
+                entry.key,
+                entry
+                    .size
+                    .map(format_size_text)
+                    .unwrap_or(String::from("? MB")),
+                entry
+                    .unused_since_s
+                    .map(format_timestamp_text)
+                    .unwrap_or(String::from("?")),
+                match entry.files.is_empty() {
+                    true => String::from("-"),
+                    false => entry.files.join(", "),
+                },
+                match entry.locations.is_empty() {
+                    true => String::from("-"),
+                    false => entry.locations.join(", "),
+                }
+            ),
+            log_target,
+        )
+        .await;
+    }
+    if let Some(remaining_keys) = &report.remaining_keys {
+        log(
+            &format!(
+                "unused after maintenance: {} keys, {} dropped or moved",
+                remaining_keys.len(),
+                report
+                    .entries
+                    .iter()
+                    .filter(|x| !remaining_keys.contains(&x.key))
+                    .count()
+            ),
+            log_target,
+        )
+        .await;
+    }
+}
+
+async fn coverage_status(repo_path: &Path, log_target: &mut LogTarget<'_>) {
+    let report: CoverageReport = read_repo_state(repo_path, COVERAGE_REPORT_NAME);
+    if report.generated_s == 0 {
+        log("numcopies: no report, run maintain first", log_target).await;
+        return;
+    }
+
+    log(
+        &format!(
+            "numcopies: {}, {} files, {} below numcopies, {} files in one place only ({})",
+            report.health_text(),
+            report.lacking_files.len(),
+            format_size_text(report.lacking_bytes()),
+            report.single_copy_files.len(),
+            format_timestamp_text(report.generated_s)
+        ),
+        log_target,
+    )
+    .await;
+    for (location, holding) in &report.locations {
+        log(
+            &format!(
+                "  in {}: {} files, {}",
+                location,
+                holding.files,
+                format_size_text(holding.bytes)
+            ),
+            log_target,
+        )
+        .await;
+    }
+    for (label, files) in [
+        ("lacking", &report.lacking_files),
+        ("only", &report.single_copy_files),
+    ] {
+        for file in files {
+            log(
+                &format!(
+                    "  {} {} {}, in: {}",
+                    label,
+                    file.file,
+                    file.size
+                        .map(format_size_text)
+                        .unwrap_or(String::from("? MB")),
+                    match file.locations.is_empty() {
+                        true => String::from("-"),
+                        false => file.locations.join(", "),
+                    }
+                ),
+                log_target,
+            )
+            .await;
+        }
+    }
+}
+
+async fn drill_status(repo_path: &Path, log_target: &mut LogTarget<'_>) {
+    let record: DrillRecord = read_repo_state(repo_path, DRILL_RECORD_NAME);
+    if record.remotes.is_empty() {
+        log("drill: no record, run drill first", log_target).await;
+        return;
+    }
+
+    log("drill:", log_target).await;
+    let mut remotes: Vec<_> = record.remotes.iter().collect();
+    remotes.sort_by_key(|(remote, _)| *remote);
+    for (remote, remote_record) in remotes {
+        log(
+            &format!(
+                "  {}: {} of {} restored, {} of {} overall, {} ({})",
+                remote,
+                remote_record.restored_ct,
+                remote_record.sampled_ct,
+                remote_record.total_restored_ct,
+                remote_record.total_sampled_ct,
+                remote_record.throughput_text(),
+                format_timestamp_text(remote_record.drilled_s)
+            ),
+            log_target,
+        )
+        .await;
+    }
+}
+
+async fn mass_change_status(repo_path: &Path, log_target: &mut LogTarget<'_>) {
+    let report: MassChangeReport = read_repo_state(repo_path, MASS_CHANGE_REPORT_NAME);
+    if report.generated_s == 0 {
+        return;
+    }
+
+    log(
+        &format!(
+            "mass change: sync held back, {} deleted, {} modified ({}), run sync --allow-mass-change once checked",
+            report.deleted.len(),
+            report.modified.len(),
+            format_timestamp_text(report.generated_s)
+        ),
+        log_target,
+    )
+    .await;
+    for (label, paths) in [("deleted", &report.deleted), ("modified", &report.modified)] {
+        for path in paths {
+            log(&format!("  {} {}", label, path), log_target).await;
+        }
+    }
+}
+
+pub(crate) async fn status(repo_paths: &Vec<PathBuf>, log_target: &mut LogTarget<'_>) {
+    for repo_path in repo_paths {
+        log(&format!("{}", repo_path.display()), log_target).await;
+        mass_change_status(repo_path, log_target).await;
+        unused_status(repo_path, log_target).await;
+        coverage_status(repo_path, log_target).await;
+        drill_status(repo_path, log_target).await;
+    }
+}
@@ -1,358 +1,948 @@
 use filetime::FileTime;
-use glob::glob;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 use std::time::SystemTime;
 use tokio::process::Command;
 use walkdir::WalkDir;
 
-use super::{command_output_logfile, log, test_available_remotes, LogTarget};
+use crate::config::{Config, EmbeddedCopyMode, RepoConfig};
 
-async fn make_embedded_git_copies(search_path: &PathBuf, log_target: &mut LogTarget<'_>) {
-    const COPY_BASE_PATH: &str = "Copies";
+use super::guard::mass_change_guard;
+use super::preferred::reconcile_preferred;
+use super::unchanged::{
+    assume_unchanged, clear_assume_unchanged, record_unchanged_sync, recover_assume_unchanged,
+    unchanged_paths,
+};
+use super::{
+    command_output_logfile, command_output_quiet, find_embedded_gits, lock_repo, log,
+    test_available_remotes, LogTarget,
+};
+
+/// Sorted lines of a command listing refs with their objects, None when it failed
+async fn ref_lines(command: &mut Command) -> Option<Vec<String>> {
+    let output = command_output_quiet(command)
+        .await
+        .filter(|x| x.status.success())?;
+    let mut lines: Vec<String> = from_utf8(&output.stdout)
+        .ok()?
+        .lines()
+        .map(String::from)
+        .collect();
+    lines.sort();
+    Some(lines)
+}
+
+async fn bundle_embedded_git(
+    master_path: &Path,
+    copy_path: &Path,
+    copy_name: &str,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let worktree_path = master_path.parent().unwrap();
+    let copy_tmp_path = copy_path.with_extension("bundle.tmp");
+
+    if let Err(e) = fs::create_dir_all(copy_path.parent().unwrap()) {
+        log(&format!("error {} (mkdir, {:?})", copy_name, e), log_target).await;
+        return false;
+    }
+
+    // the bundle holds every ref and HEAD, as long as they did not move it is up to date
+    if copy_path.exists() {
+        let bundle_refs = ref_lines(
+            Command::new("git")
+                .args(["bundle", "list-heads"])
+                .arg(copy_path)
+                .current_dir(worktree_path),
+        )
+        .await;
+        let repo_refs = ref_lines(
+            Command::new("git")
+                .args(["show-ref", "--head"])
+                .current_dir(worktree_path),
+        )
+        .await;
+        if bundle_refs.is_some() && bundle_refs == repo_refs {
+            log(&format!("ok {} (unchanged)", copy_name), log_target).await;
+            return true;
+        }
+    }
+
+    // write next to the previous bundle and only replace it once verified
+    let bundle_ok = command_output_logfile(
+        Command::new("git")
+            .args(["bundle", "create"])
+            .arg(&copy_tmp_path)
+            .arg("--all")
+            .current_dir(worktree_path),
+        format!("git-bundle-create {}", copy_name),
+        log_target,
+    )
+    .await
+        && command_output_logfile(
+            Command::new("git")
+                .args(["bundle", "verify", "--quiet"])
+                .arg(&copy_tmp_path)
+                .current_dir(worktree_path),
+            format!("git-bundle-verify {}", copy_name),
+            log_target,
+        )
+        .await;
+
+    match bundle_ok {
+        true => match fs::rename(&copy_tmp_path, copy_path) {
+            Ok(()) => {
+                log(&format!("ok {}", copy_name), log_target).await;
+                true
+            }
+            Err(e) => {
+                log(
+                    &format!("error {} (rename, {:?})", copy_name, e),
+                    log_target,
+                )
+                .await;
+                false
+            }
+        },
+        false => {
+            fs::remove_file(&copy_tmp_path).ok();
+            log(&format!("error {} (bundle not ok)", copy_name), log_target).await;
+            false
+        }
+    }
+}
+
+async fn mirror_embedded_git(
+    master_path: &Path,
+    copy_path: &Path,
+    copy_name: &str,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let worktree_path = master_path.parent().unwrap();
+
+    let mirror_ok = match copy_path.exists() {
+        true => {
+            // refuse to fetch into a copy that was made by another mode
+            let is_mirror = command_output_quiet(
+                Command::new("git")
+                    .args(["config", "--get", "remote.origin.mirror"])
+                    .current_dir(copy_path),
+            )
+            .await
+            .is_some_and(|output| from_utf8(&output.stdout).unwrap_or("").trim() == "true");
+            if !is_mirror {
+                log(
+                    &format!("error {} (not a mirror, remove to recreate)", copy_name),
+                    log_target,
+                )
+                .await;
+                return false;
+            }
+            command_output_logfile(
+                Command::new("git")
+                    .args(["fetch", "--prune", "origin"])
+                    .current_dir(copy_path),
+                format!("git-fetch {}", copy_name),
+                log_target,
+            )
+            .await
+        }
+        false => {
+            if let Err(e) = fs::create_dir_all(copy_path.parent().unwrap()) {
+                log(&format!("error {} (mkdir, {:?})", copy_name, e), log_target).await;
+                return false;
+            }
+            command_output_logfile(
+                Command::new("git")
+                    .args(["clone", "--mirror", "--quiet"])
+                    .arg(worktree_path)
+                    .arg(copy_path),
+                format!("git-clone-mirror {}", copy_name),
+                log_target,
+            )
+            .await
+        }
+    };
+
+    let verify_ok = mirror_ok
+        && command_output_logfile(
+            Command::new("git")
+                .args(["fsck", "--connectivity-only", "--no-progress"])
+                .current_dir(copy_path),
+            format!("git-fsck {}", copy_name),
+            log_target,
+        )
+        .await;
 
     log(
-        &format!("make-embedded-git-copies {}", search_path.display()),
+        &format!(
+            "{} {}",
+            match verify_ok {
+                true => "ok",
+                false => "error",
+            },
+            copy_name
+        ),
         log_target,
     )
     .await;
+    verify_ok
+}
 
-    for entry in glob(
-        Path::new(search_path)
-            .join(Path::new("**/.git"))
-            .to_str()
-            .unwrap(),
-    )
-    .expect("unable to read glob pattern")
-    {
-        match entry {
-            Ok(master_path) => {
-                if master_path.display().to_string()
-                    == Path::new(search_path).join(".git").display().to_string()
+const COPY_MANIFEST_NAME: &str = ".archiver-manifest.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct CopyManifest {
+    files: BTreeMap<PathBuf, CopyManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CopyManifestEntry {
+    size: u64,
+    sha256: String,
+    mtime_s: u64,
+}
+
+fn copy_manifest_entry(
+    file_path: &Path,
+    prev_entry: Option<&CopyManifestEntry>,
+) -> Option<CopyManifestEntry> {
+    let metadata = file_path.metadata().ok()?;
+    let mtime_s = metadata
+        .modified()
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+
+    // only rehash when size or mtime moved
+    if let Some(prev_entry) = prev_entry {
+        if prev_entry.size == metadata.len() && prev_entry.mtime_s == mtime_s {
+            return Some(prev_entry.clone());
+        }
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(file_path).ok()?, &mut hasher).ok()?;
+    Some(CopyManifestEntry {
+        size: metadata.len(),
+        sha256: format!("{:x}", hasher.finalize()),
+        mtime_s,
+    })
+}
+
+/// Whether a file still has the size and content recorded for it
+fn matches_copy_manifest_entry(file_path: &Path, manifest_entry: &CopyManifestEntry) -> bool {
+    copy_manifest_entry(file_path, None)
+        .is_some_and(|x| x.size == manifest_entry.size && x.sha256 == manifest_entry.sha256)
+}
+
+fn read_copy_manifest(copy_path: &Path) -> Option<CopyManifest> {
+    fs::read_to_string(copy_path.join(COPY_MANIFEST_NAME))
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+}
+
+fn write_copy_manifest(copy_path: &Path, manifest: &CopyManifest) -> Result<(), io::Error> {
+    let manifest_path = copy_path.join(COPY_MANIFEST_NAME);
+    let manifest_tmp_path = manifest_path.with_extension("tmp");
+    fs::write(&manifest_tmp_path, serde_json::to_vec(manifest)?)?;
+    fs::rename(&manifest_tmp_path, &manifest_path)
+}
+
+/// Returns why the copy of a file is out of date, if it is
+fn copy_entry_change(
+    master_entry_path: &Path,
+    copy_entry_path: &Path,
+    entry_relpath: &Path,
+    copy_prev_mtime: Option<SystemTime>,
+    manifests: Option<(&CopyManifest, &mut CopyManifest)>,
+) -> Option<String> {
+    match manifests {
+        Some((prev_manifest, next_manifest)) => {
+            let prev_entry = prev_manifest.files.get(entry_relpath);
+            let Some(master_entry) = copy_manifest_entry(master_entry_path, prev_entry) else {
+                return Some(String::from("unreadable"));
+            };
+            // without a previous manifest, the copy itself tells whether it is current
+            let copy_entry = match prev_entry {
+                Some(prev_entry) => Some(prev_entry.clone()),
+                None => copy_manifest_entry(copy_entry_path, None),
+            };
+            let change = match copy_entry {
+                Some(copy_entry)
+                    if copy_entry.size == master_entry.size
+                        && copy_entry.sha256 == master_entry.sha256 =>
                 {
-                    continue;
-                }
-                let repository_name = &master_path
-                    .parent()
-                    .unwrap()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap();
-                let copy_name = format!("{}.git", repository_name);
-
-                let copy_path =
-                    &master_path.join(&format!("../../{}/{}", COPY_BASE_PATH, copy_name));
-
-                let mut copy_prev_mtime: Option<SystemTime> = None;
-                match copy_path.exists() {
-                    true => {
-                        copy_prev_mtime = Some(copy_path.metadata().unwrap().modified().unwrap());
-                        log(
-                            &format!(
-                                "ok {}/ (mtime: {})",
-                                copy_name,
-                                copy_prev_mtime
-                                    .unwrap()
-                                    .duration_since(SystemTime::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs()
-                            ),
-                            log_target,
-                        )
-                        .await;
-                    }
-                    false => {
-                        match fs::create_dir_all(copy_path) {
-                            Ok(()) => log(&format!("mkdir {}/", copy_name), log_target).await,
-                            Err(e) => {
-                                log(&format!("error {} (mkdir, {:?})", copy_name, e), log_target)
-                                    .await;
-                                continue;
-                            }
-                        };
-                    }
+                    None
                 }
+                _ => Some(format!("sha256 {}", &master_entry.sha256[..12])),
+            };
+            next_manifest
+                .files
+                .insert(entry_relpath.to_path_buf(), master_entry);
+            change
+        }
+        None => {
+            let Ok(master_mtime) = master_entry_path.metadata().and_then(|x| x.modified()) else {
+                return Some(String::from("unreadable"));
+            };
+            match copy_prev_mtime.is_none() || master_mtime > copy_prev_mtime.unwrap() {
+                true => Some(format!(
+                    "+{}",
+                    master_mtime
+                        .duration_since(copy_prev_mtime.unwrap_or(SystemTime::UNIX_EPOCH))
+                        .unwrap_or_default()
+                        .as_secs()
+                )),
+                false => None,
+            }
+        }
+    }
+}
+
+/// Brings back a consistent copy after an interrupted run, dropping unfinished ones
+async fn recover_embedded_git_files(
+    copy_path: &Path,
+    copy_tmp_path: &Path,
+    copy_old_path: &Path,
+    copy_name: &str,
+    log_target: &mut LogTarget<'_>,
+) {
+    if copy_tmp_path.exists() {
+        match fs::remove_dir_all(copy_tmp_path) {
+            Ok(()) => log(&format!("rm {}.tmp/ (unfinished)", copy_name), log_target).await,
+            Err(e) => {
+                log(
+                    &format!("error {}.tmp (rm, {:?})", copy_name, e),
+                    log_target,
+                )
+                .await
+            }
+        }
+    }
+    if copy_old_path.exists() {
+        let recover_result = match copy_path.exists() {
+            true => fs::remove_dir_all(copy_old_path),
+            false => fs::rename(copy_old_path, copy_path),
+        };
+        match recover_result {
+            Ok(()) => log(&format!("recover {}/", copy_name), log_target).await,
+            Err(e) => {
+                log(
+                    &format!("error {} (recover, {:?})", copy_name, e),
+                    log_target,
+                )
+                .await
+            }
+        }
+    }
+}
+
+/// Lets the owner write a copied file, git objects being read-only
+fn set_owner_writable(file_path: &Path) -> io::Result<()> {
+    let mut perms = fs::metadata(file_path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if perms.mode() & 0o200 != 0 {
+            return Ok(());
+        }
+        perms.set_mode(perms.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    {
+        if !perms.readonly() {
+            return Ok(());
+        }
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+    }
+    fs::set_permissions(file_path, perms)
+}
+
+async fn copy_embedded_git_files(
+    master_path: &Path,
+    copy_path: &Path,
+    copy_name: &str,
+    uses_manifest: bool,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    // the copy is built next to the previous one and only swapped in once complete
+    let copy_tmp_path = &copy_path.with_extension("git.tmp");
+    let copy_old_path = &copy_path.with_extension("git.old");
+    recover_embedded_git_files(
+        copy_path,
+        copy_tmp_path,
+        copy_old_path,
+        copy_name,
+        log_target,
+    )
+    .await;
+
+    let prev_manifest = read_copy_manifest(copy_path).unwrap_or_default();
+    let mut next_manifest = uses_manifest.then(CopyManifest::default);
+
+    let copy_prev_mtime: Option<SystemTime> = match copy_path.metadata().and_then(|x| x.modified())
+    {
+        Ok(copy_mtime) => {
+            log(
+                &format!(
+                    "ok {}/ (mtime: {})",
+                    copy_name,
+                    copy_mtime
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                ),
+                log_target,
+            )
+            .await;
+            Some(copy_mtime)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            log(&format!("error {} (mtime, {:?})", copy_name, e), log_target).await;
+            return false;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(copy_tmp_path) {
+        log(&format!("error {} (mkdir, {:?})", copy_name, e), log_target).await;
+        return false;
+    }
+
+    let mut copy_unprocessed_entry_relpaths: Vec<PathBuf> = vec![];
+    for direntry in WalkDir::new(copy_path)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(Result::ok)
+    {
+        let entry_relpath = direntry
+            .path()
+            .strip_prefix(copy_path)
+            .unwrap()
+            .to_path_buf();
+        if entry_relpath != Path::new(COPY_MANIFEST_NAME) {
+            copy_unprocessed_entry_relpaths.push(entry_relpath);
+        }
+    }
+
+    let mut is_complete = true;
+    for entry in WalkDir::new(master_path).min_depth(1) {
+        let direntry = &match entry {
+            Ok(direntry) => direntry,
+            // git removes files while it runs, those are not part of the copy
+            Err(e)
+                if e.io_error()
+                    .is_some_and(|x| x.kind() == io::ErrorKind::NotFound) =>
+            {
+                continue
+            }
+            Err(e) => {
+                is_complete = false;
+                log(&format!("error {} (walk, {:?})", copy_name, e), log_target).await;
+                continue;
+            }
+        };
+        // lock files belong to a running git process, copying them would lock the copy
+        if !direntry.file_type().is_dir() && direntry.path().extension() == Some("lock".as_ref()) {
+            continue;
+        }
+        let entry_relpath = direntry
+            .path()
+            .strip_prefix(master_path)
+            .unwrap()
+            .to_path_buf();
+        let entry_relpath_display = format!("{}/{}", copy_name, &entry_relpath.display());
+        let master_entry_path: PathBuf = master_path.join(&entry_relpath);
+        let copy_entry_path: PathBuf = copy_path.join(&entry_relpath);
+        let copy_tmp_entry_path: PathBuf = copy_tmp_path.join(&entry_relpath);
+        let is_copied = copy_unprocessed_entry_relpaths.contains(&entry_relpath);
+        copy_unprocessed_entry_relpaths
+            .retain(|x: &PathBuf| x.as_path() != entry_relpath.as_path());
 
-                let mut copy_unprocessed_entry_relpaths: Vec<PathBuf> = vec![];
-                for entry in WalkDir::new(&copy_path) {
-                    let direntry = &entry.unwrap();
-                    let entry_relpath = direntry
-                        .path()
-                        .strip_prefix(&copy_path)
-                        .unwrap()
-                        .to_path_buf();
-                    if !&entry_relpath.as_os_str().is_empty() {
-                        copy_unprocessed_entry_relpaths.push(entry_relpath.to_path_buf());
+        if direntry.file_type().is_dir() {
+            match fs::create_dir_all(&copy_tmp_entry_path) {
+                Ok(()) => {
+                    if !is_copied {
+                        log(&format!("mkdir {}/", entry_relpath_display), log_target).await;
                     }
                 }
+                Err(e) => {
+                    is_complete = false;
+                    log(
+                        &format!("error {} (mkdir, {:?})", entry_relpath_display, e),
+                        log_target,
+                    )
+                    .await;
+                }
+            }
+            continue;
+        }
 
-                for entry in WalkDir::new(&master_path) {
-                    let direntry = &entry.unwrap();
-                    let entry_relpath = direntry
-                        .path()
-                        .strip_prefix(&master_path)
-                        .unwrap()
-                        .to_path_buf();
-
-                    if !entry_relpath.as_os_str().is_empty() {
-                        let entry_relpath_display =
-                            format!("{}/{}", copy_name, &entry_relpath.as_path().display());
-                        let master_entry_path: PathBuf =
-                            Path::new(&master_path).join(&entry_relpath);
-                        let copy_entry_path: PathBuf = Path::new(&copy_path).join(&entry_relpath);
-
-                        match direntry.metadata().unwrap().is_dir() {
-                            true => {
-                                match copy_unprocessed_entry_relpaths.contains(&entry_relpath) {
-                                    true => {
-                                        match copy_entry_path.exists() {
-                                            true => {}
-                                            false => match fs::create_dir_all(copy_entry_path) {
-                                                Ok(()) => {
-                                                    log(
-                                                        &format!(
-                                                            "mkdir {}/",
-                                                            entry_relpath_display
-                                                        ),
-                                                        log_target,
-                                                    )
-                                                    .await;
-                                                }
-                                                Err(e) => {
-                                                    log(
-                                                        &format!(
-                                                            "error {} (mkdir, {:?})",
-                                                            entry_relpath_display, e
-                                                        ),
-                                                        log_target,
-                                                    )
-                                                    .await;
-                                                }
-                                            },
-                                        };
-                                        copy_unprocessed_entry_relpaths.retain(|x: &PathBuf| {
-                                            x.as_path() != entry_relpath.as_path()
-                                        });
-                                    }
-                                    false => match fs::create_dir_all(copy_entry_path) {
-                                        Ok(()) => {
-                                            log(
-                                                &format!("mkdir {}/", entry_relpath_display),
-                                                log_target,
-                                            )
-                                            .await;
-                                        }
-                                        Err(e) => {
-                                            log(
-                                                &format!(
-                                                    "error {} (mkdir, {:?})",
-                                                    entry_relpath_display, e
-                                                ),
-                                                log_target,
-                                            )
-                                            .await;
-                                        }
-                                    },
-                                };
-                            }
-                            false => {
-                                match copy_unprocessed_entry_relpaths.contains(&entry_relpath) {
-                                    true => {
-                                        let master_mtime = master_entry_path
-                                            .metadata()
-                                            .unwrap()
-                                            .modified()
-                                            .unwrap();
-                                        match copy_prev_mtime.is_none()
-                                            || master_mtime > copy_prev_mtime.unwrap()
-                                        {
-                                            true => {
-                                                match fs::copy(&master_entry_path, &copy_entry_path)
-                                                {
-                                                    Ok(_) => {
-                                                        let mut perms =
-                                                            fs::metadata(&copy_entry_path)
-                                                                .unwrap()
-                                                                .permissions();
-                                                        if perms.readonly() {
-                                                            perms.set_readonly(false);
-                                                            fs::set_permissions(
-                                                                &copy_entry_path,
-                                                                perms,
-                                                            )
-                                                            .unwrap();
-                                                        }
-                                                        log(
-                                                            &format!(
-                                                                "cp {} (+{})",
-                                                                entry_relpath_display,
-                                                                master_mtime
-                                                                    .duration_since(
-                                                                        copy_prev_mtime.unwrap_or(
-                                                                            SystemTime::UNIX_EPOCH
-                                                                        )
-                                                                    )
-                                                                    .unwrap()
-                                                                    .as_secs()
-                                                            ),
-                                                            log_target,
-                                                        )
-                                                        .await;
-                                                    }
-                                                    Err(e) => {
-                                                        log(
-                                                            &format!(
-                                                                "error {} (cp, {:?})",
-                                                                entry_relpath_display, e
-                                                            ),
-                                                            log_target,
-                                                        )
-                                                        .await;
-                                                    }
-                                                };
-                                            }
-                                            false => {}
-                                        }
-                                        copy_unprocessed_entry_relpaths.retain(|x: &PathBuf| {
-                                            x.as_path() != entry_relpath.as_path()
-                                        });
-                                    }
-                                    false => {
-                                        match fs::copy(&master_entry_path, &copy_entry_path) {
-                                            Ok(_) => {
-                                                let mut perms = fs::metadata(&copy_entry_path)
-                                                    .unwrap()
-                                                    .permissions();
-                                                if perms.readonly() {
-                                                    perms.set_readonly(false);
-                                                    fs::set_permissions(&copy_entry_path, perms)
-                                                        .unwrap();
-                                                }
-                                                log(
-                                                    &format!("cp {}", entry_relpath_display),
-                                                    log_target,
-                                                )
-                                                .await;
-                                            }
-                                            Err(e) => {
-                                                log(
-                                                    &format!(
-                                                        "error {} (cp, {:?})",
-                                                        entry_relpath_display, e
-                                                    ),
-                                                    log_target,
-                                                )
-                                                .await;
-                                            }
-                                        };
-                                    }
-                                }
-                            }
-                        };
+        let change = match is_copied {
+            true => copy_entry_change(
+                &master_entry_path,
+                &copy_entry_path,
+                &entry_relpath,
+                copy_prev_mtime,
+                next_manifest.as_mut().map(|x| (&prev_manifest, x)),
+            ),
+            false => {
+                if let Some(next_manifest) = next_manifest.as_mut() {
+                    if let Some(master_entry) = copy_manifest_entry(&master_entry_path, None) {
+                        next_manifest
+                            .files
+                            .insert(entry_relpath.clone(), master_entry);
                     }
                 }
+                Some(String::from("new"))
+            }
+        };
 
-                for copy_entry_unprocessed_relpath in copy_unprocessed_entry_relpaths {
-                    let copy_entry_path: PathBuf =
-                        Path::new(&copy_path).join(&copy_entry_unprocessed_relpath);
-                    let entry_relpath_display = format!(
-                        "{}/{}",
-                        copy_name,
-                        &copy_entry_unprocessed_relpath.as_path().display()
-                    );
-
-                    match copy_entry_path.is_dir() {
-                        true => match fs::remove_dir(copy_entry_path) {
-                            Ok(_) => {
-                                log(&format!("rmdir {}", entry_relpath_display), log_target).await;
-                            }
-                            Err(e) => {
-                                log(
-                                    &format!("error {} (rmdir, {:?})", entry_relpath_display, e),
-                                    log_target,
-                                )
-                                .await;
-                            }
-                        },
-                        false => match fs::remove_file(copy_entry_path) {
-                            Ok(_) => {
-                                log(&format!("rm {}", entry_relpath_display), log_target).await;
-                            }
-                            Err(e) => {
-                                log(
-                                    &format!("error {} (copy, {:?})", entry_relpath_display, e),
-                                    log_target,
-                                )
-                                .await;
-                            }
-                        },
+        // unchanged files are shared with the previous copy instead of copied again
+        if change.is_none() && fs::hard_link(&copy_entry_path, &copy_tmp_entry_path).is_ok() {
+            continue;
+        }
+        match fs::copy(&master_entry_path, &copy_tmp_entry_path)
+            .and_then(|_| set_owner_writable(&copy_tmp_entry_path))
+        {
+            Ok(()) => {
+                // the manifest has to describe the copy, the master may change while it is read
+                if let Some(next_manifest) = &next_manifest {
+                    let is_hash_ok = next_manifest
+                        .files
+                        .get(&entry_relpath)
+                        .is_some_and(|x| matches_copy_manifest_entry(&copy_tmp_entry_path, x));
+                    if !is_hash_ok {
+                        is_complete = false;
+                        log(
+                            &format!("error {} (hash mismatch)", entry_relpath_display),
+                            log_target,
+                        )
+                        .await;
+                        continue;
                     }
                 }
-                filetime::set_file_mtime(copy_path, FileTime::now()).unwrap();
+                if let Some(change) = change {
+                    log(
+                        &format!("cp {} ({})", entry_relpath_display, change),
+                        log_target,
+                    )
+                    .await;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Some(next_manifest) = next_manifest.as_mut() {
+                    next_manifest.files.remove(&entry_relpath);
+                }
+                log(
+                    &format!("skip {} (vanished)", entry_relpath_display),
+                    log_target,
+                )
+                .await;
             }
             Err(e) => {
-                log(&format!("{:?}", e), log_target).await;
+                is_complete = false;
+                log(
+                    &format!("error {} (cp, {:?})", entry_relpath_display, e),
+                    log_target,
+                )
+                .await;
+            }
+        };
+    }
+
+    if let Some(next_manifest) = &next_manifest {
+        if let Err(e) = write_copy_manifest(copy_tmp_path, next_manifest) {
+            is_complete = false;
+            log(
+                &format!("error {} (manifest, {:?})", copy_name, e),
+                log_target,
+            )
+            .await;
+        }
+    }
+
+    if !is_complete {
+        fs::remove_dir_all(copy_tmp_path).ok();
+        log(
+            &format!("error {} (incomplete, previous copy kept)", copy_name),
+            log_target,
+        )
+        .await;
+        return false;
+    }
+
+    // an interrupted swap is finished by the recovery on the next run
+    if let Err(e) = filetime::set_file_mtime(copy_tmp_path, FileTime::now()) {
+        fs::remove_dir_all(copy_tmp_path).ok();
+        log(&format!("error {} (mtime, {:?})", copy_name, e), log_target).await;
+        return false;
+    }
+    let swap_result = match copy_path.exists() {
+        true => fs::rename(copy_path, copy_old_path)
+            .and_then(|_| fs::rename(copy_tmp_path, copy_path))
+            .and_then(|_| fs::remove_dir_all(copy_old_path)),
+        false => fs::rename(copy_tmp_path, copy_path),
+    };
+    if let Err(e) = swap_result {
+        log(&format!("error {} (swap, {:?})", copy_name, e), log_target).await;
+        return false;
+    }
+
+    for copy_entry_unprocessed_relpath in copy_unprocessed_entry_relpaths {
+        log(
+            &format!(
+                "rm {}/{}",
+                copy_name,
+                copy_entry_unprocessed_relpath.display()
+            ),
+            log_target,
+        )
+        .await;
+    }
+    true
+}
+
+const COPY_BASE_PATH: &str = "Copies";
+
+fn encode_embedded_git_copy_name(relpath: &Path) -> String {
+    relpath
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F")
+}
+
+fn decode_embedded_git_copy_name(copy_name: &str) -> String {
+    copy_name.replace("%2F", "/").replace("%25", "%")
+}
+
+fn embedded_copy_root_path(search_path: &Path, repo_config: &RepoConfig) -> Option<PathBuf> {
+    // a relative root is resolved against the annex repo, an absolute one is used as is
+    repo_config
+        .embedded_copy_root
+        .as_ref()
+        .map(|x| search_path.join(x))
+}
+
+fn embedded_git_copy_path(
+    search_path: &Path,
+    master_path: &Path,
+    repo_config: &RepoConfig,
+) -> (PathBuf, String) {
+    let worktree_path = master_path.parent().unwrap();
+    let copy_extension = match repo_config.embedded_copy_mode.unwrap_or_default() {
+        EmbeddedCopyMode::Files | EmbeddedCopyMode::Mirror => "git",
+        EmbeddedCopyMode::Bundle => "bundle",
+    };
+
+    match embedded_copy_root_path(search_path, repo_config) {
+        Some(copy_root_path) => {
+            let copy_name = format!(
+                "{}.{}",
+                encode_embedded_git_copy_name(worktree_path.strip_prefix(search_path).unwrap()),
+                copy_extension
+            );
+            (copy_root_path.join(&copy_name), copy_name)
+        }
+        None => {
+            let copy_name = format!(
+                "{}.{}",
+                worktree_path.file_name().unwrap().to_str().unwrap(),
+                copy_extension
+            );
+            (
+                master_path.join(format!("../../{}/{}", COPY_BASE_PATH, copy_name)),
+                copy_name,
+            )
+        }
+    }
+}
+
+async fn make_embedded_git_copies(
+    search_path: &Path,
+    repo_config: &RepoConfig,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    log(
+        &format!("make-embedded-git-copies {}", search_path.display()),
+        log_target,
+    )
+    .await;
+
+    let Some(master_paths) = find_embedded_gits(search_path, repo_config, log_target).await else {
+        log(
+            &format!("make-embedded-git-copies {} not ok", search_path.display()),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    let mut is_ok = true;
+    let mut copy_names: Vec<String> = vec![];
+
+    for master_path in master_paths {
+        let (copy_path, copy_name) = embedded_git_copy_path(search_path, &master_path, repo_config);
+        copy_names.push(copy_name.clone());
+
+        is_ok &= match repo_config.embedded_copy_mode.unwrap_or_default() {
+            EmbeddedCopyMode::Files => {
+                copy_embedded_git_files(
+                    &master_path,
+                    &copy_path,
+                    &copy_name,
+                    repo_config.embedded_copy_manifest.unwrap_or(false),
+                    log_target,
+                )
+                .await
+            }
+            EmbeddedCopyMode::Bundle => {
+                bundle_embedded_git(&master_path, &copy_path, &copy_name, log_target).await
+            }
+            EmbeddedCopyMode::Mirror => {
+                mirror_embedded_git(&master_path, &copy_path, &copy_name, log_target).await
+            }
+        };
+    }
+
+    // copies are only left in place, removing them is up to the user
+    if let Some(copy_root_path) = &embedded_copy_root_path(search_path, repo_config) {
+        for entry in fs::read_dir(copy_root_path)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+        {
+            let copy_name = entry.file_name().to_string_lossy().to_string();
+            if copy_name.ends_with(".tmp")
+                || copy_name.ends_with(".old")
+                || copy_names.contains(&copy_name)
+            {
+                continue;
+            }
+            log(
+                &format!(
+                    "stale {} (no embedded git at {})",
+                    copy_name,
+                    decode_embedded_git_copy_name(
+                        copy_name
+                            .rsplit_once('.')
+                            .map(|(stem, _)| stem)
+                            .unwrap_or(&copy_name)
+                    )
+                ),
+                log_target,
+            )
+            .await;
+        }
+    }
+
+    log(
+        &format!(
+            "make-embedded-git-copies {} {}",
+            search_path.display(),
+            match is_ok {
+                true => "ok",
+                false => "not ok",
+            }
+        ),
+        log_target,
+    )
+    .await;
+    is_ok
+}
+
+async fn verify_embedded_git_files(
+    copy_path: &Path,
+    copy_name: &str,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let Some(manifest) = read_copy_manifest(copy_path) else {
+        log(&format!("error {} (no manifest)", copy_name), log_target).await;
+        return false;
+    };
+
+    let mut is_ok = true;
+    let mut unlisted_relpaths: Vec<PathBuf> = vec![];
+    for direntry in WalkDir::new(copy_path)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|x| !x.file_type().is_dir())
+    {
+        let entry_relpath = direntry.path().strip_prefix(copy_path).unwrap();
+        if entry_relpath != Path::new(COPY_MANIFEST_NAME)
+            && !manifest.files.contains_key(entry_relpath)
+        {
+            unlisted_relpaths.push(entry_relpath.to_path_buf());
+        }
+    }
+    for entry_relpath in unlisted_relpaths {
+        is_ok = false;
+        log(
+            &format!("unlisted {}/{}", copy_name, entry_relpath.display()),
+            log_target,
+        )
+        .await;
+    }
+
+    for (entry_relpath, manifest_entry) in &manifest.files {
+        let entry_relpath_display = format!("{}/{}", copy_name, entry_relpath.display());
+        match copy_manifest_entry(&copy_path.join(entry_relpath), None) {
+            Some(copy_entry)
+                if copy_entry.size == manifest_entry.size
+                    && copy_entry.sha256 == manifest_entry.sha256 => {}
+            Some(_) => {
+                is_ok = false;
+                log(&format!("mismatch {}", entry_relpath_display), log_target).await;
             }
+            None => {
+                is_ok = false;
+                log(&format!("missing {}", entry_relpath_display), log_target).await;
+            }
+        }
+    }
+    is_ok
+}
+
+async fn verify_embedded_git_copies(
+    search_path: &Path,
+    repo_config: &RepoConfig,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    log(
+        &format!("verify-embedded-git-copies {}", search_path.display()),
+        log_target,
+    )
+    .await;
+
+    let Some(master_paths) = find_embedded_gits(search_path, repo_config, log_target).await else {
+        log(
+            &format!(
+                "verify-embedded-git-copies {} not ok",
+                search_path.display()
+            ),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    let mut is_ok = true;
+    for master_path in master_paths {
+        let (copy_path, copy_name) = embedded_git_copy_path(search_path, &master_path, repo_config);
+        if !copy_path.exists() {
+            is_ok = false;
+            log(&format!("missing {}", copy_name), log_target).await;
+            continue;
         }
+
+        let copy_ok = match repo_config.embedded_copy_mode.unwrap_or_default() {
+            EmbeddedCopyMode::Files => {
+                verify_embedded_git_files(&copy_path, &copy_name, log_target).await
+            }
+            EmbeddedCopyMode::Bundle => {
+                command_output_logfile(
+                    Command::new("git")
+                        .args(["bundle", "verify", "--quiet"])
+                        .arg(&copy_path)
+                        .current_dir(master_path.parent().unwrap()),
+                    format!("git-bundle-verify {}", copy_name),
+                    log_target,
+                )
+                .await
+            }
+            EmbeddedCopyMode::Mirror => {
+                command_output_logfile(
+                    Command::new("git")
+                        .args(["fsck", "--connectivity-only", "--no-progress"])
+                        .current_dir(&copy_path),
+                    format!("git-fsck {}", copy_name),
+                    log_target,
+                )
+                .await
+            }
+        };
+        is_ok &= copy_ok;
+        log(
+            &format!(
+                "{} {}",
+                match copy_ok {
+                    true => "ok",
+                    false => "not ok",
+                },
+                copy_name
+            ),
+            log_target,
+        )
+        .await;
     }
+
     log(
-        &format!("make-embedded-git-copies {} ok", search_path.display()),
+        &format!(
+            "verify-embedded-git-copies {} {}",
+            search_path.display(),
+            match is_ok {
+                true => "ok",
+                false => "not ok",
+            }
+        ),
         log_target,
     )
     .await;
+    is_ok
 }
 
 pub(crate) async fn sync(
-    repo_paths: &Vec<PathBuf>,
+    repo_paths: &[PathBuf],
     includes_all: bool,
+    allows_mass_change: bool,
+    config: &Config,
     log_target: &mut LogTarget<'_>,
     notify_progress: impl Fn(String),
 ) -> Result<Vec<bool>, ()> {
     let mut repo_ok: Vec<bool> = vec![];
     for (repo_index, repo_path) in repo_paths.iter().enumerate() {
         notify_progress(format!("{}/{}", repo_index + 1, repo_paths.len()));
+        let Some(_repo_lock) = lock_repo(repo_path, "sync", log_target).await else {
+            repo_ok.push(false);
+            continue;
+        };
 
+        let repo_config = config.repo_config(repo_path);
         let available_remotes = test_available_remotes(repo_path, log_target).await;
-        make_embedded_git_copies(repo_path, log_target).await;
-
-        let unchanged_stdout = &Command::new("git")
-            .args(["ls-files", "-z", ":(attr:annex.archiver.unchanged)*"])
-            .current_dir(repo_path)
-            .output()
-            .await
-            .expect("unable to get file list")
-            .stdout;
-
-        let unchanged_paths: Vec<&str> = Vec::from_iter(
-            from_utf8(unchanged_stdout)
-                .unwrap()
-                .trim()
-                .split_terminator("\u{0}"),
-        );
+        let is_copies_ok = make_embedded_git_copies(repo_path, &repo_config, log_target).await;
+        // assist then gets and drops content according to the reconciled settings, it would
+        // follow stale ones otherwise
+        let is_preferred_ok = reconcile_preferred(repo_path, &repo_config, log_target).await;
 
+        recover_assume_unchanged(repo_path, log_target).await;
+        let mut is_unchanged_ok = true;
         if !includes_all {
-            command_output_logfile(
-                Command::new("git")
-                    .args(
-                        [
-                            vec!["update-index", "--assume-unchanged"],
-                            unchanged_paths.clone(),
-                        ]
-                        .concat(),
-                    )
-                    .current_dir(repo_path),
-                format!(
-                    "git-update-index-assume-unchanged {:?}",
-                    repo_path.display()
-                ),
-                log_target,
-            )
-            .await;
+            // without the list, unchanged files would be committed like any other
+            let Some(paths) = unchanged_paths(repo_path).await else {
+                log(
+                    &format!("git-annex-assist {:?} not ok", repo_path.display()),
+                    log_target,
+                )
+                .await;
+                repo_ok.push(false);
+                continue;
+            };
+            // files left without the flag would be committed, those with it are cleared below
+            is_unchanged_ok = assume_unchanged(repo_path, &paths, log_target).await;
         }
 
-        repo_ok.push(if available_remotes.is_empty() {
+        // a repository mass deleting or rewriting files keeps its changes to itself
+        let is_guard_ok =
+            allows_mass_change || mass_change_guard(repo_path, &repo_config, log_target).await;
+
+        let is_assist_ready =
+            is_preferred_ok && is_unchanged_ok && is_guard_ok && !available_remotes.is_empty();
+        let is_assist_ok = if !is_assist_ready {
             log(
                 &format!("git-annex-assist {:?} not ok", repo_path.display()),
                 log_target,
@@ -380,25 +970,15 @@ pub(crate) async fn sync(
                 log_target,
             )
             .await
-        });
+        };
+        repo_ok.push(is_copies_ok && is_assist_ok);
 
-        command_output_logfile(
-            Command::new("git")
-                .args(
-                    [
-                        vec!["update-index", "--no-assume-unchanged"],
-                        unchanged_paths,
-                    ]
-                    .concat(),
-                )
-                .current_dir(repo_path),
-            format!(
-                "git-update-index-no-assume-unchanged {:?}",
-                repo_path.display()
-            ),
-            log_target,
-        )
-        .await;
+        clear_assume_unchanged(repo_path, log_target).await;
+        if includes_all && is_assist_ok {
+            if let Some(paths) = unchanged_paths(repo_path).await {
+                record_unchanged_sync(repo_path, &paths, log_target).await;
+            }
+        }
     }
     log(
         match repo_ok.contains(&false) {
@@ -410,3 +990,59 @@ pub(crate) async fn sync(
     .await;
     Ok(repo_ok)
 }
+
+pub(crate) async fn copies(
+    repo_paths: &[PathBuf],
+    verifies: bool,
+    config: &Config,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let mut is_ok = true;
+    for repo_path in repo_paths {
+        let Some(_repo_lock) = lock_repo(repo_path, "copies", log_target).await else {
+            is_ok = false;
+            continue;
+        };
+        let repo_config = config.repo_config(repo_path);
+        match verifies {
+            true => is_ok &= verify_embedded_git_copies(repo_path, &repo_config, log_target).await,
+            false => is_ok &= make_embedded_git_copies(repo_path, &repo_config, log_target).await,
+        }
+    }
+    is_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_git_copy_name_round_trips() {
+        for relpath in ["a", "a/b/c", "100%/x", "x%2Fy", "%252F/%"] {
+            let copy_name = encode_embedded_git_copy_name(Path::new(relpath));
+            assert!(!copy_name.contains('/'));
+            assert_eq!(decode_embedded_git_copy_name(&copy_name), relpath);
+        }
+        assert_eq!(
+            encode_embedded_git_copy_name(Path::new("a/b%c")),
+            "a%2Fb%25c"
+        );
+    }
+
+    #[test]
+    fn copy_manifest_entry_matches_content_only() {
+        let file_path = std::env::temp_dir().join(format!(
+            "git-annex-archiver-test-manifest-{}",
+            std::process::id()
+        ));
+        fs::write(&file_path, "abc").unwrap();
+        let manifest_entry = copy_manifest_entry(&file_path, None).unwrap();
+
+        filetime::set_file_mtime(&file_path, FileTime::from_unix_time(0, 0)).unwrap();
+        assert!(matches_copy_manifest_entry(&file_path, &manifest_entry));
+        fs::write(&file_path, "abd").unwrap();
+        assert!(!matches_copy_manifest_entry(&file_path, &manifest_entry));
+        fs::remove_file(&file_path).unwrap();
+        assert!(!matches_copy_manifest_entry(&file_path, &manifest_entry));
+    }
+}
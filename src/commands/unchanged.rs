@@ -0,0 +1,542 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use tokio::process::Command;
+
+use crate::format::format_timestamp_text;
+
+use super::unused::unix_now_s;
+use super::{
+    command_output_logfile, command_output_quiet, lock_repo, log, read_repo_state, repo_state_path,
+    write_repo_state, LogTarget,
+};
+
+const UNCHANGED_ATTR: &str = "annex.archiver.unchanged";
+const UNCHANGED_JOURNAL_NAME: &str = "unchanged-journal.json";
+const UNCHANGED_RECORD_NAME: &str = "unchanged-record.json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct UnchangedJournal {
+    /// Paths given the assume-unchanged bit by a sync, until it clears it
+    paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UnchangedRecord {
+    /// Last sync including all files, by path with the unchanged attribute at the time
+    synced_s: HashMap<String, u64>,
+}
+
+/// Tracked paths with the unchanged attribute, None when they could not be listed
+pub(crate) async fn unchanged_paths(repo_path: &Path) -> Option<Vec<String>> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["ls-files", "-z", &format!(":(attr:{})*", UNCHANGED_ATTR)])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(
+        from_utf8(&output.stdout)
+            .ok()?
+            .split_terminator('\0')
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Tracked paths currently having the assume-unchanged bit, None when they could not be listed
+async fn assumed_unchanged_paths(repo_path: &Path) -> Option<Vec<String>> {
+    let output = command_output_quiet(
+        Command::new("git")
+            .args(["ls-files", "-v", "-z"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success())?;
+    Some(
+        from_utf8(&output.stdout)
+            .ok()?
+            .split_terminator('\0')
+            .filter_map(|x| x.split_once(' '))
+            .filter(|(tag, _)| tag.chars().all(|x| x.is_ascii_lowercase()))
+            .map(|(_, path)| path.to_string())
+            .collect(),
+    )
+}
+
+/// Gives paths the assume-unchanged bit, journaling them first so an interrupted sync
+/// can be cleaned up by the next one
+pub(crate) async fn assume_unchanged(
+    repo_path: &Path,
+    paths: &[String],
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let journal = UnchangedJournal {
+        paths: paths.to_vec(),
+    };
+    if let Err(e) = write_repo_state(repo_path, UNCHANGED_JOURNAL_NAME, &journal) {
+        log(&format!("unchanged-journal not ok ({})", e), log_target).await;
+        return false;
+    }
+
+    command_output_logfile(
+        Command::new("git")
+            .args(["update-index", "--assume-unchanged"])
+            .args(paths)
+            .current_dir(repo_path),
+        format!(
+            "git-update-index-assume-unchanged {:?}",
+            repo_path.display()
+        ),
+        log_target,
+    )
+    .await
+}
+
+/// Clears the assume-unchanged bit of the journaled paths still having it
+pub(crate) async fn clear_assume_unchanged(
+    repo_path: &Path,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let journal: UnchangedJournal = read_repo_state(repo_path, UNCHANGED_JOURNAL_NAME);
+    if journal.paths.is_empty() {
+        return true;
+    }
+
+    let Some(assumed_paths) = assumed_unchanged_paths(repo_path).await else {
+        log(
+            &format!(
+                "git-update-index-no-assume-unchanged {:?} not ok",
+                repo_path.display()
+            ),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    let is_ok = command_output_logfile(
+        Command::new("git")
+            .args(["update-index", "--no-assume-unchanged"])
+            .args(journal.paths.iter().filter(|x| assumed_paths.contains(x)))
+            .current_dir(repo_path),
+        format!(
+            "git-update-index-no-assume-unchanged {:?}",
+            repo_path.display()
+        ),
+        log_target,
+    )
+    .await;
+    if is_ok {
+        fs::remove_file(repo_state_path(repo_path, UNCHANGED_JOURNAL_NAME)).ok();
+    }
+    is_ok
+}
+
+/// Whether a sync was interrupted while paths had the assume-unchanged bit
+pub fn is_assume_unchanged_pending(repo_path: &Path) -> bool {
+    !read_repo_state::<UnchangedJournal>(repo_path, UNCHANGED_JOURNAL_NAME)
+        .paths
+        .is_empty()
+}
+
+/// Cleans up after a sync interrupted while paths had the assume-unchanged bit
+pub(crate) async fn recover_assume_unchanged(
+    repo_path: &Path,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let journal: UnchangedJournal = read_repo_state(repo_path, UNCHANGED_JOURNAL_NAME);
+    if journal.paths.is_empty() {
+        return true;
+    }
+
+    log(
+        &format!(
+            "unchanged-journal {:?} left by an interrupted sync ({} paths)",
+            repo_path.display(),
+            journal.paths.len()
+        ),
+        log_target,
+    )
+    .await;
+    clear_assume_unchanged(repo_path, log_target).await
+}
+
+/// Lists the paths left with the assume-unchanged bit by the archiver, optionally clearing it
+pub(crate) async fn unchanged_flags(
+    repo_paths: &[PathBuf],
+    resets: bool,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let mut is_ok = true;
+    for repo_path in repo_paths {
+        let journal: UnchangedJournal = read_repo_state(repo_path, UNCHANGED_JOURNAL_NAME);
+        let (Some(attr_paths), Some(assumed_paths)) = (
+            unchanged_paths(repo_path).await,
+            assumed_unchanged_paths(repo_path).await,
+        ) else {
+            log(
+                &format!("unchanged-flags {} not ok", repo_path.display()),
+                log_target,
+            )
+            .await;
+            is_ok = false;
+            continue;
+        };
+        let flagged_paths: Vec<String> = assumed_paths
+            .into_iter()
+            .filter(|x| journal.paths.contains(x) || attr_paths.contains(x))
+            .collect();
+
+        log(
+            &format!(
+                "unchanged-flags {} ({} paths)",
+                repo_path.display(),
+                flagged_paths.len()
+            ),
+            log_target,
+        )
+        .await;
+        for flagged_path in &flagged_paths {
+            log(&format!("assume-unchanged {}", flagged_path), log_target).await;
+        }
+        if !resets {
+            continue;
+        }
+        let Some(_repo_lock) = lock_repo(repo_path, "unchanged flags", log_target).await else {
+            is_ok = false;
+            continue;
+        };
+
+        let is_reset_ok = flagged_paths.is_empty()
+            || command_output_logfile(
+                Command::new("git")
+                    .args(["update-index", "--no-assume-unchanged"])
+                    .args(&flagged_paths)
+                    .current_dir(repo_path),
+                format!(
+                    "git-update-index-no-assume-unchanged {:?}",
+                    repo_path.display()
+                ),
+                log_target,
+            )
+            .await;
+        if is_reset_ok {
+            fs::remove_file(repo_state_path(repo_path, UNCHANGED_JOURNAL_NAME)).ok();
+        }
+        is_ok &= is_reset_ok;
+    }
+    is_ok
+}
+
+/// Remembers the unchanged paths were included in a sync
+pub(crate) async fn record_unchanged_sync(
+    repo_path: &Path,
+    paths: &[String],
+    log_target: &mut LogTarget<'_>,
+) {
+    let now_s = unix_now_s();
+    let record = UnchangedRecord {
+        synced_s: paths.iter().map(|x| (x.clone(), now_s)).collect(),
+    };
+    if let Err(e) = write_repo_state(repo_path, UNCHANGED_RECORD_NAME, &record) {
+        log(&format!("unchanged-record not ok ({})", e), log_target).await;
+    }
+}
+
+/// Path relative to the repository, from one relative to the current folder or the repository
+fn repo_relative_path(repo_path: &Path, path: &str) -> String {
+    let relative_path = env::current_dir()
+        .ok()
+        .and_then(|x| x.join(path).canonicalize().ok())
+        .zip(repo_path.canonicalize().ok())
+        .and_then(|(x, repo_path)| {
+            x.strip_prefix(repo_path)
+                .ok()
+                .map(|x| x.to_string_lossy().to_string())
+        })
+        .unwrap_or(path.to_string());
+    relative_path.trim_matches('/').to_string()
+}
+
+/// Anchored gitattributes pattern matching a path, and everything below it for a folder
+fn unchanged_pattern(repo_path: &Path, relative_path: &str, base_path: &str) -> Option<String> {
+    let pattern_path = match base_path.is_empty() {
+        true => relative_path,
+        false => relative_path.strip_prefix(&format!("{}/", base_path))?,
+    };
+    let pattern = match repo_path.join(relative_path).is_dir() {
+        true => format!("/{}/**", pattern_path),
+        false => format!("/{}", pattern_path),
+    };
+    Some(match pattern.contains(char::is_whitespace) {
+        true => format!("{:?}", pattern),
+        false => pattern,
+    })
+}
+
+fn is_unchanged_line(line: &str, pattern: &str) -> bool {
+    line.strip_prefix(pattern)
+        .filter(|x| x.starts_with(char::is_whitespace))
+        .is_some_and(|x| x.split_whitespace().any(|x| x == UNCHANGED_ATTR))
+}
+
+/// Gives paths the unchanged attribute in the .gitattributes of the repository
+pub(crate) async fn unchanged_add(
+    repo_path: &Path,
+    paths: &[String],
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let Some(_repo_lock) = lock_repo(repo_path, "unchanged add", log_target).await else {
+        return false;
+    };
+    let attributes_path = repo_path.join(".gitattributes");
+    let mut attributes = fs::read_to_string(&attributes_path).unwrap_or_default();
+    for path in paths {
+        let relative_path = repo_relative_path(repo_path, path);
+        let pattern = unchanged_pattern(repo_path, &relative_path, "").unwrap();
+        if attributes.lines().any(|x| is_unchanged_line(x, &pattern)) {
+            log(&format!("unchanged-add {} already", pattern), log_target).await;
+            continue;
+        }
+        if !attributes.is_empty() && !attributes.ends_with('\n') {
+            attributes.push('\n');
+        }
+        attributes.push_str(&format!("{} {}\n", pattern, UNCHANGED_ATTR));
+        log(&format!("unchanged-add {}", pattern), log_target).await;
+    }
+    match fs::write(&attributes_path, attributes) {
+        Ok(_) => true,
+        Err(e) => {
+            log(
+                &format!("unchanged-add {} not ok ({})", attributes_path.display(), e),
+                log_target,
+            )
+            .await;
+            false
+        }
+    }
+}
+
+/// Takes the unchanged attribute off paths, in whichever .gitattributes gives it
+pub(crate) async fn unchanged_remove(
+    repo_path: &Path,
+    paths: &[String],
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let Some(_repo_lock) = lock_repo(repo_path, "unchanged remove", log_target).await else {
+        return false;
+    };
+    let Some(attributes_output) = command_output_quiet(
+        Command::new("git")
+            .args([
+                "ls-files",
+                "-z",
+                "--cached",
+                "--others",
+                "--exclude-standard",
+            ])
+            .args([".gitattributes", "*/.gitattributes"])
+            .current_dir(repo_path),
+    )
+    .await
+    .filter(|x| x.status.success()) else {
+        log(
+            &format!("unchanged-remove {:?} not ok", repo_path.display()),
+            log_target,
+        )
+        .await;
+        return false;
+    };
+    let attributes_files: Vec<&str> = from_utf8(&attributes_output.stdout)
+        .unwrap_or_default()
+        .split_terminator('\0')
+        .collect();
+
+    let mut is_ok = true;
+    for path in paths {
+        let relative_path = repo_relative_path(repo_path, path);
+        let mut is_removed = false;
+        for attributes_file in &attributes_files {
+            let base_path = attributes_file
+                .strip_suffix(".gitattributes")
+                .unwrap()
+                .trim_end_matches('/');
+            let Some(pattern) = unchanged_pattern(repo_path, &relative_path, base_path) else {
+                continue;
+            };
+            let attributes_path = repo_path.join(attributes_file);
+            let attributes = fs::read_to_string(&attributes_path).unwrap_or_default();
+            // anchored or not, as written by hand
+            let patterns = [pattern.clone(), pattern.replacen('/', "", 1)];
+            if !attributes
+                .lines()
+                .any(|x| patterns.iter().any(|pattern| is_unchanged_line(x, pattern)))
+            {
+                continue;
+            }
+
+            let kept_lines: Vec<String> = attributes
+                .lines()
+                .filter_map(|x| {
+                    let Some(pattern) = patterns
+                        .iter()
+                        .find(|pattern| is_unchanged_line(x, pattern))
+                    else {
+                        return Some(x.to_string());
+                    };
+                    // other attributes on the line stay
+                    let kept_attrs: Vec<&str> = x[pattern.len()..]
+                        .split_whitespace()
+                        .filter(|x| *x != UNCHANGED_ATTR)
+                        .collect();
+                    (!kept_attrs.is_empty())
+                        .then(|| format!("{} {}", pattern, kept_attrs.join(" ")))
+                })
+                .collect();
+            let attributes = match kept_lines.is_empty() {
+                true => String::new(),
+                false => format!("{}\n", kept_lines.join("\n")),
+            };
+            match fs::write(&attributes_path, attributes) {
+                Ok(_) => {
+                    log(
+                        &format!("unchanged-remove {} in {}", pattern, attributes_file),
+                        log_target,
+                    )
+                    .await;
+                    is_removed = true;
+                }
+                Err(e) => {
+                    log(
+                        &format!("unchanged-remove {} not ok ({})", pattern, e),
+                        log_target,
+                    )
+                    .await;
+                    is_ok = false;
+                }
+            }
+        }
+        if !is_removed {
+            log(
+                &format!("unchanged-remove {} not found", relative_path),
+                log_target,
+            )
+            .await;
+            is_ok = false;
+        }
+    }
+
+    let still_paths = unchanged_paths(repo_path).await.unwrap_or_default();
+    for path in paths {
+        let relative_path = repo_relative_path(repo_path, path);
+        if still_paths
+            .iter()
+            .any(|x| *x == relative_path || x.starts_with(&format!("{}/", relative_path)))
+        {
+            log(
+                &format!(
+                    "unchanged-remove {} still unchanged through another pattern",
+                    relative_path
+                ),
+                log_target,
+            )
+            .await;
+        }
+    }
+    is_ok
+}
+
+/// Lists the tracked paths with the unchanged attribute and when a sync last included them
+pub(crate) async fn unchanged_list(repo_paths: &[PathBuf], log_target: &mut LogTarget<'_>) {
+    for repo_path in repo_paths {
+        let record: UnchangedRecord = read_repo_state(repo_path, UNCHANGED_RECORD_NAME);
+        let Some(paths) = unchanged_paths(repo_path).await else {
+            log(
+                &format!("unchanged {} not ok", repo_path.display()),
+                log_target,
+            )
+            .await;
+            continue;
+        };
+        log(
+            &format!("unchanged {} ({} paths)", repo_path.display(), paths.len()),
+            log_target,
+        )
+        .await;
+        for path in paths {
+            log(
+                &format!(
+                    "  {}, last synced {}",
+                    path,
+                    record
+                        .synced_s
+                        .get(&path)
+                        .map(|x| format_timestamp_text(*x))
+                        .unwrap_or(String::from("never"))
+                ),
+                log_target,
+            )
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_pattern_anchors_to_base() {
+        let repo_path = env::temp_dir().join(format!(
+            "git-annex-archiver-test-unchanged-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(repo_path.join("photos/2020")).unwrap();
+
+        assert_eq!(
+            unchanged_pattern(&repo_path, "notes.txt", ""),
+            Some(String::from("/notes.txt"))
+        );
+        assert_eq!(
+            unchanged_pattern(&repo_path, "photos/2020", ""),
+            Some(String::from("/photos/2020/**"))
+        );
+        assert_eq!(
+            unchanged_pattern(&repo_path, "photos/2020", "photos"),
+            Some(String::from("/2020/**"))
+        );
+        assert_eq!(
+            unchanged_pattern(&repo_path, "photos/my trip.jpg", "photos"),
+            Some(String::from("\"/my trip.jpg\""))
+        );
+        assert_eq!(unchanged_pattern(&repo_path, "photosx/a", "photos"), None);
+        assert_eq!(unchanged_pattern(&repo_path, "videos/a", "photos"), None);
+        fs::remove_dir_all(&repo_path).unwrap();
+    }
+
+    #[test]
+    fn is_unchanged_line_needs_pattern_and_attr() {
+        assert!(is_unchanged_line(
+            "/notes.txt annex.archiver.unchanged",
+            "/notes.txt"
+        ));
+        assert!(is_unchanged_line(
+            "/notes.txt\t-diff annex.archiver.unchanged",
+            "/notes.txt"
+        ));
+        assert!(!is_unchanged_line(
+            "/notes.txt.bak annex.archiver.unchanged",
+            "/notes.txt"
+        ));
+        assert!(!is_unchanged_line("/notes.txt -diff", "/notes.txt"));
+        assert!(!is_unchanged_line(
+            "/notes.txt annex.archiver.unchanged-not",
+            "/notes.txt"
+        ));
+        assert!(!is_unchanged_line("/notes.txt", "/notes.txt"));
+    }
+}
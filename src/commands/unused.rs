@@ -0,0 +1,374 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::process::Command;
+use tokio::time::Instant;
+
+use crate::config::RepoConfig;
+
+use super::{
+    command_output_lines_until, command_output_logfile_until, command_output_quiet, log,
+    read_repo_state, write_repo_state, LogTarget,
+};
+
+pub const UNUSED_RECORD_NAME: &str = "unused-record.json";
+const DROPUNUSED_GRACE_D: u64 = 30;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct UnusedRecord {
+    /// First time each key was seen unused, by remote name or "here"
+    pub locations: HashMap<String, HashMap<String, u64>>,
+}
+
+pub struct UnusedKey {
+    pub number: u64,
+    pub key: String,
+}
+
+/// Size encoded in a key by its backend, such as SHA256E-s1234--...
+pub fn key_size(key: &str) -> Option<u64> {
+    key.split("--")
+        .next()?
+        .split('-')
+        .find_map(|x| x.strip_prefix('s')?.parse().ok())
+}
+
+pub fn unix_now_s() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub async fn annex_unused(
+    repo_path: &Path,
+    remote: Option<&str>,
+    deadline: Option<Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> Option<Vec<UnusedKey>> {
+    lazy_static! {
+        static ref UNUSED_KEY_REGEX: Regex = Regex::new(r"^\s+(\d+)\s+(\S+)$").unwrap();
+    }
+
+    let (is_ok, lines) = command_output_lines_until(
+        Command::new("git")
+            .args(["annex", "unused"])
+            .args(remote.map(|x| format!("--from={}", x)))
+            .current_dir(repo_path),
+        format!(
+            "git-annex-unused {:?} {}",
+            repo_path.display(),
+            remote.unwrap_or("here")
+        ),
+        deadline,
+        log_target,
+    )
+    .await;
+    if !is_ok {
+        return None;
+    }
+    Some(
+        lines
+            .iter()
+            .filter_map(|x| UNUSED_KEY_REGEX.captures(x))
+            .map(|x| UnusedKey {
+                number: x[1].parse().unwrap(),
+                key: x[2].to_string(),
+            })
+            .collect(),
+    )
+}
+
+/// Drops or quarantines the content unused at a location, keeping it for the grace period,
+/// and counts the bytes actually dropped or moved, stopping at the given deadline
+pub async fn drop_unused(
+    repo_path: &Path,
+    remote: Option<&str>,
+    repo_config: &RepoConfig,
+    record: &mut UnusedRecord,
+    dropped_bytes: &mut u64,
+    deadline: Option<Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    let location = remote.unwrap_or("here");
+    let Some(unused_keys) = annex_unused(repo_path, remote, deadline, log_target).await else {
+        return false;
+    };
+
+    let now_s = unix_now_s();
+    let prev_unused = record.locations.remove(location).unwrap_or_default();
+    let unused: HashMap<String, u64> = unused_keys
+        .iter()
+        .map(|x| {
+            (
+                x.key.clone(),
+                prev_unused.get(&x.key).copied().unwrap_or(now_s),
+            )
+        })
+        .collect();
+
+    // quarantined content waits out the grace period on the quarantine remote instead
+    let grace_d = repo_config.dropunused_grace_d.unwrap_or(DROPUNUSED_GRACE_D);
+    let quarantine_remote = repo_config
+        .dropunused_quarantine_remote
+        .as_deref()
+        .filter(|x| *x != location);
+
+    let mut drop_numbers: Vec<String> = vec![];
+    let mut drop_bytes: u64 = 0;
+    let mut move_keys: Vec<(&str, u64)> = vec![];
+    for unused_key in &unused_keys {
+        let unused_d = (now_s - unused[&unused_key.key]) / 86400;
+        if quarantine_remote.is_none() && unused_d < grace_d {
+            log(
+                &format!(
+                    "keep-unused {} (unused for {} of {} days)",
+                    unused_key.key, unused_d, grace_d
+                ),
+                log_target,
+            )
+            .await;
+            continue;
+        }
+        let size = key_size(&unused_key.key);
+        let planned_bytes = drop_bytes + move_keys.iter().map(|(_, x)| x).sum::<u64>();
+        if let Some(max_mb) = repo_config.dropunused_max_mb {
+            // a key without a size could be of any size, so it never fits the cap
+            if size.is_none_or(|x| *dropped_bytes + planned_bytes + x > max_mb * 1000 * 1000) {
+                log(
+                    &format!(
+                        "keep-unused {} ({} MB per run{})",
+                        unused_key.key,
+                        max_mb,
+                        match size {
+                            Some(_) => "",
+                            None => ", size unknown",
+                        }
+                    ),
+                    log_target,
+                )
+                .await;
+                continue;
+            }
+        }
+        let size = size.unwrap_or(0);
+        match quarantine_remote {
+            Some(_) => move_keys.push((&unused_key.key, size)),
+            None => {
+                drop_numbers.push(unused_key.number.to_string());
+                drop_bytes += size;
+            }
+        }
+    }
+
+    let mut is_ok = true;
+
+    if !drop_numbers.is_empty() {
+        let is_drop_ok = command_output_logfile_until(
+            Command::new("git")
+                .args(["annex", "dropunused"])
+                .args(&drop_numbers)
+                .args(remote.map(|x| format!("--from={}", x)))
+                .current_dir(repo_path),
+            format!(
+                "git-annex-dropunused {:?} {}",
+                repo_path.display(),
+                location
+            ),
+            deadline,
+            log_target,
+        )
+        .await;
+        if is_drop_ok {
+            *dropped_bytes += drop_bytes;
+        }
+        is_ok &= is_drop_ok;
+    }
+    if let Some(quarantine_remote) = quarantine_remote {
+        for (key, size) in move_keys {
+            if deadline.is_some_and(|x| Instant::now() >= x) {
+                is_ok = false;
+                break;
+            }
+            let is_move_ok = command_output_logfile_until(
+                Command::new("git")
+                    .args(["annex", "move", &format!("--key={}", key)])
+                    .args(remote.map(|x| format!("--from={}", x)))
+                    .arg(format!("--to={}", quarantine_remote))
+                    .current_dir(repo_path),
+                format!(
+                    "git-annex-move-unused {:?} {} {}",
+                    repo_path.display(),
+                    location,
+                    key
+                ),
+                deadline,
+                log_target,
+            )
+            .await;
+            if is_move_ok {
+                *dropped_bytes += size;
+            }
+            is_ok &= is_move_ok;
+        }
+    }
+
+    record.locations.insert(location.to_string(), unused);
+    is_ok
+}
+
+pub const UNUSED_REPORT_NAME: &str = "unused-report.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct UnusedReport {
+    pub generated_s: u64,
+    pub entries: Vec<UnusedReportEntry>,
+    /// Keys still unused here once maintenance is done
+    pub remaining_keys: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnusedReportEntry {
+    pub key: String,
+    pub size: Option<u64>,
+    pub unused_since_s: Option<u64>,
+    /// Files the key used to belong to
+    pub files: Vec<String>,
+    /// Repositories holding the content, by description
+    pub locations: Vec<String>,
+}
+
+async fn key_history_files(repo_path: &Path, key: &str) -> Vec<String> {
+    let mut files: Vec<String> = vec![];
+    let stdout = command_output_quiet(
+        Command::new("git")
+            .args(["log", "--all", "--format=", "--name-only"])
+            .arg(format!("-S{}", key))
+            .current_dir(repo_path),
+    )
+    .await
+    .map(|x| x.stdout)
+    .unwrap_or_default();
+    for file in String::from_utf8_lossy(&stdout).lines() {
+        if !file.is_empty() && !files.iter().any(|x| x == file) {
+            files.push(file.to_string());
+        }
+    }
+    files
+}
+
+async fn key_locations(repo_path: &Path, key: &str) -> Vec<String> {
+    let stdout = command_output_quiet(
+        Command::new("git")
+            .args(["annex", "whereis", "--json", &format!("--key={}", key)])
+            .current_dir(repo_path),
+    )
+    .await
+    .map(|x| x.stdout)
+    .unwrap_or_default();
+    serde_json::from_slice::<serde_json::Value>(&stdout)
+        .ok()
+        .and_then(|x| x["whereis"].as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(|x| match x["here"].as_bool() {
+            Some(true) => String::from("here"),
+            _ => x["description"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect()
+}
+
+/// Writes which files unused keys belonged to and where their content is, returning whether
+/// the report was written before the deadline
+pub async fn write_unused_report(
+    repo_path: &Path,
+    unused_keys: &[UnusedKey],
+    deadline: Option<Instant>,
+    log_target: &mut LogTarget<'_>,
+) -> bool {
+    log(
+        &format!("unused-report {}", repo_path.display()),
+        log_target,
+    )
+    .await;
+
+    let record: UnusedRecord = read_repo_state(repo_path, UNUSED_RECORD_NAME);
+    let mut report = UnusedReport {
+        generated_s: unix_now_s(),
+        ..Default::default()
+    };
+    for unused_key in unused_keys {
+        if deadline.is_some_and(|x| Instant::now() >= x) {
+            log(
+                &format!("unused-report {} interrupted", repo_path.display()),
+                log_target,
+            )
+            .await;
+            return false;
+        }
+        report.entries.push(UnusedReportEntry {
+            key: unused_key.key.clone(),
+            size: key_size(&unused_key.key),
+            unused_since_s: record
+                .locations
+                .get("here")
+                .and_then(|x| x.get(&unused_key.key))
+                .copied(),
+            files: key_history_files(repo_path, &unused_key.key).await,
+            locations: key_locations(repo_path, &unused_key.key).await,
+        });
+    }
+
+    let written = write_repo_state(repo_path, UNUSED_REPORT_NAME, &report);
+    log(
+        &format!(
+            "unused-report {} {}",
+            repo_path.display(),
+            match &written {
+                Ok(_) => String::from("ok"),
+                Err(e) => format!("not ok ({})", e),
+            }
+        ),
+        log_target,
+    )
+    .await;
+    written.is_ok()
+}
+
+pub async fn update_unused_report_remaining(
+    repo_path: &Path,
+    deadline: Option<Instant>,
+    log_target: &mut LogTarget<'_>,
+) {
+    let Some(unused_keys) = annex_unused(repo_path, None, deadline, log_target).await else {
+        return;
+    };
+    let mut report: UnusedReport = read_repo_state(repo_path, UNUSED_REPORT_NAME);
+    report.remaining_keys = Some(unused_keys.into_iter().map(|x| x.key).collect());
+    if let Err(e) = write_repo_state(repo_path, UNUSED_REPORT_NAME, &report) {
+        log(&format!("unused-report not ok ({})", e), log_target).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_size_reads_size_field() {
+        assert_eq!(
+            key_size("SHA256E-s1048576--3a7bd3e2360a3d29eea436fcfb7e44c735d117c4.mkv"),
+            Some(1048576)
+        );
+        assert_eq!(
+            key_size("SHA1-s0--da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            Some(0)
+        );
+        assert_eq!(key_size("WORM-s42-m1700000000--photos%2Fa.jpg"), Some(42));
+        assert_eq!(key_size("URL--https&c%%example.com%s12"), None);
+        assert_eq!(key_size("SHA256E-m1700000000--s12.txt"), None);
+    }
+}
@@ -0,0 +1,83 @@
+use notify::event::{EventKind, ModifyKind};
+use notify::{Event, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Component, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Sender};
+
+#[cfg(not(target_os = "macos"))]
+use notify::event::CreateKind;
+#[cfg(not(target_os = "macos"))]
+use walkdir::WalkDir;
+
+pub async fn watch(
+    repo_paths: &Vec<PathBuf>,
+    debounce_s: u64,
+    changed_tx: Sender<Vec<PathBuf>>,
+) -> notify::Result<()> {
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        event_tx.send(event).ok();
+    })?;
+
+    for repo_path in repo_paths {
+        // FSEvents streams are recursive at no cost, inotify needs a watch per directory
+        #[cfg(target_os = "macos")]
+        watcher.watch(repo_path, RecursiveMode::Recursive)?;
+
+        #[cfg(not(target_os = "macos"))]
+        for entry in WalkDir::new(repo_path)
+            .into_iter()
+            .filter_entry(|x| x.file_name() != ".git")
+            .filter_map(Result::ok)
+            .filter(|x| x.file_type().is_dir())
+        {
+            watcher.watch(entry.path(), RecursiveMode::NonRecursive)?;
+        }
+    }
+
+    let mut changed_paths = HashSet::<PathBuf>::new();
+    loop {
+        let event = match changed_paths.is_empty() {
+            true => event_rx.recv().await,
+            false => {
+                match tokio::time::timeout(Duration::from_secs(debounce_s), event_rx.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        if changed_tx
+                            .send(changed_paths.drain().collect())
+                            .await
+                            .is_err()
+                        {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                }
+            }
+        };
+
+        match event {
+            Some(Ok(event)) => match event.kind {
+                #[cfg(not(target_os = "macos"))]
+                EventKind::Create(CreateKind::Folder) => {
+                    for path in event.paths {
+                        watcher.watch(&path, RecursiveMode::NonRecursive).ok();
+                    }
+                }
+                EventKind::Modify(ModifyKind::Metadata(_)) => {
+                    changed_paths.extend(event.paths.into_iter().filter(|x| {
+                        !x.components()
+                            .any(|component| component == Component::Normal(".git".as_ref()))
+                            && x.symlink_metadata()
+                                .map(|metadata| !metadata.is_dir())
+                                .unwrap_or(false)
+                    }));
+                }
+                _ => (),
+            },
+            Some(Err(_)) => (),
+            None => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use glob::Pattern;
+use home::home_dir;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocateQuotaPolicy {
+    #[default]
+    Accessed,
+    Largest,
+    Oldest,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocateRuleAction {
+    Keep,
+    Drop,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AllocateRule {
+    pub action: AllocateRuleAction,
+    /// Glob pattern, relative to the repository
+    pub path: Option<String>,
+    /// git-annex matching options, split like shell words so quotes group an option value
+    pub matching: Option<String>,
+    pub older_than_d: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddedCopyMode {
+    #[default]
+    Files,
+    Bundle,
+    Mirror,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsckScope {
+    #[default]
+    All,
+    Worktree,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FsckConfig {
+    pub incremental_schedule: Option<String>,
+    pub time_limit: Option<String>,
+    pub scope: Option<FsckScope>,
+    /// Skips fsck and dropunused, for remotes too slow or costly to read back
+    pub excluded: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PreferredConfig {
+    /// Preferred content expression, as set by git annex wanted
+    pub wanted: Option<String>,
+    pub required: Option<String>,
+    pub groups: Option<Vec<String>>,
+}
+
+impl FsckConfig {
+    fn or(&self, fallback: &FsckConfig) -> FsckConfig {
+        FsckConfig {
+            incremental_schedule: self
+                .incremental_schedule
+                .clone()
+                .or(fallback.incremental_schedule.clone()),
+            time_limit: self.time_limit.clone().or(fallback.time_limit.clone()),
+            scope: self.scope.or(fallback.scope),
+            excluded: self.excluded.or(fallback.excluded),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GcMode {
+    Gc,
+    Repack,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RepoConfig {
+    pub allocate_quota_mb: Option<u64>,
+    pub allocate_quota_policy: Option<AllocateQuotaPolicy>,
+    #[serde(default)]
+    pub allocate_rules: Vec<AllocateRule>,
+    pub embedded_copy_mode: Option<EmbeddedCopyMode>,
+    /// Folder for all embedded git copies, relative to the repository unless absolute
+    pub embedded_copy_root: Option<String>,
+    /// Track size and hash of copied files, only copying files whose content changed
+    pub embedded_copy_manifest: Option<bool>,
+    /// Glob patterns for embedded repositories, relative to the repository
+    #[serde(default)]
+    pub embedded_git_include: Vec<String>,
+    /// Glob patterns for folders not to search, relative to the repository
+    #[serde(default)]
+    pub embedded_git_exclude: Vec<String>,
+    pub embedded_git_skip_gitlinks: Option<bool>,
+    pub embedded_git_skip_submodules: Option<bool>,
+    #[serde(default)]
+    pub fsck: FsckConfig,
+    /// Fsck settings by remote name, "here" for the repository itself
+    #[serde(default)]
+    pub fsck_remotes: HashMap<String, FsckConfig>,
+    /// Days content has to stay unused before it is dropped, 30 by default
+    pub dropunused_grace_d: Option<u64>,
+    pub dropunused_max_mb: Option<u64>,
+    /// Remote unused content is moved to, and dropped from after the grace period
+    pub dropunused_quarantine_remote: Option<String>,
+    /// Preferred content settings by remote name, "here" for the repository itself
+    #[serde(default)]
+    pub preferred: HashMap<String, PreferredConfig>,
+    pub numcopies: Option<u64>,
+    pub mincopies: Option<u64>,
+    /// Files below numcopies the repository may have and still be healthy, by default it is
+    /// unhealthy when it has more than in the previous report
+    pub numcopies_lacking_max: Option<u64>,
+    /// Deleted files above which sync refuses to commit and push, 1000 by default
+    pub sync_max_deleted: Option<u64>,
+    pub sync_max_modified: Option<u64>,
+    /// Runs git annex repair when git fsck finds problems
+    pub maintain_repair: Option<bool>,
+    pub maintain_gc: Option<GcMode>,
+    /// Compacts the git-annex branch by forgetting its history and dead repositories
+    pub maintain_forget_dead: Option<bool>,
+}
+
+impl RepoConfig {
+    pub fn fsck_config(&self, remote: Option<&str>) -> FsckConfig {
+        match self.fsck_remotes.get(remote.unwrap_or("here")) {
+            Some(remote_fsck_config) => remote_fsck_config.or(&self.fsck),
+            None => self.fsck.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    pub repo_paths: Vec<String>,
+    pub maintain_timeout_m: Option<u64>,
+    /// Time limit for each git and git-annex command
+    pub command_timeout_m: Option<u64>,
+    /// Time an operation waits for another one holding a repository, 15 by default
+    pub repo_lock_wait_m: Option<u64>,
+    pub maintain_schedule: Option<String>,
+    /// Days within which every repository and remote should have been maintained
+    pub maintain_sla_d: Option<u64>,
+    pub sync_schedule: Option<String>,
+    pub sync_unchanged_schedule: Option<String>,
+    /// Restores a sample of content from every remote, when set
+    pub drill_schedule: Option<String>,
+    /// Keys restored from each remote during a drill
+    pub drill_sample_ct: Option<usize>,
+    pub allocate_watch: Option<bool>,
+    pub allocate_watch_debounce_s: Option<u64>,
+    #[serde(default)]
+    pub repos: HashMap<String, RepoConfig>,
+}
+
+impl Config {
+    /// Rejects allocate rules selecting no files, which would otherwise apply to every file
+    fn validate(&self) -> Result<(), String> {
+        for (repo_path, repo_config) in &self.repos {
+            if repo_config
+                .allocate_rules
+                .iter()
+                .any(|x| x.path.is_none() && x.matching.is_none() && x.older_than_d.is_none())
+            {
+                return Err(format!(
+                    "allocate rule of {} needs path, matching or older_than_d",
+                    repo_path
+                ));
+            }
+            for pattern in repo_config
+                .embedded_git_include
+                .iter()
+                .chain(&repo_config.embedded_git_exclude)
+            {
+                Pattern::new(pattern).map_err(|e| {
+                    format!("embedded git pattern {} of {} ({})", pattern, repo_path, e)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn repo_config(&self, repo_path: &Path) -> RepoConfig {
+        self.repos
+            .iter()
+            .find(|(path, _)| Path::new(path) == repo_path)
+            .map(|(_, repo_config)| repo_config.clone())
+            .unwrap_or_default()
+    }
+}
+
+pub fn config_dir_path() -> PathBuf {
+    home_dir()
+        .expect("unable to find home dir")
+        .join(".config/git-annex/archiver")
+}
+
+pub fn read_config(config_dir_path: &Path) -> Result<Config, String> {
+    let config_str = fs::read_to_string(config_dir_path.join("config"))
+        .map_err(|e| format!("unable to read config ({})", e))?;
+    let config: Config =
+        toml::from_str(&config_str).map_err(|e| format!("unable to parse config ({})", e))?;
+    config
+        .validate()
+        .map_err(|e| format!("unable to parse config ({})", e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fsck_config_falls_back_per_setting() {
+        let remote_fsck_config = FsckConfig {
+            time_limit: Some(String::from("30m")),
+            excluded: Some(false),
+            ..Default::default()
+        };
+        let fsck_config = FsckConfig {
+            incremental_schedule: Some(String::from("30d")),
+            time_limit: Some(String::from("2h")),
+            scope: Some(FsckScope::Worktree),
+            excluded: Some(true),
+        };
+        let merged_fsck_config = remote_fsck_config.or(&fsck_config);
+        assert_eq!(
+            merged_fsck_config.incremental_schedule.as_deref(),
+            Some("30d")
+        );
+        assert_eq!(merged_fsck_config.time_limit.as_deref(), Some("30m"));
+        assert_eq!(merged_fsck_config.scope, Some(FsckScope::Worktree));
+        assert_eq!(merged_fsck_config.excluded, Some(false));
+
+        let repo_config = RepoConfig {
+            fsck: fsck_config,
+            fsck_remotes: HashMap::from([(String::from("nas"), remote_fsck_config)]),
+            ..Default::default()
+        };
+        assert_eq!(
+            repo_config.fsck_config(Some("nas")).time_limit.as_deref(),
+            Some("30m")
+        );
+        assert_eq!(
+            repo_config.fsck_config(Some("usb")).time_limit.as_deref(),
+            Some("2h")
+        );
+        assert_eq!(repo_config.fsck_config(None).excluded, Some(true));
+    }
+
+    #[test]
+    fn allocate_rule_rejects_unknown_fields() {
+        let config: Result<Config, _> = toml::from_str(
+            r#"
+            repo_paths = []
+            [[repos."/r".allocate_rules]]
+            action = "drop"
+            pth = "*.iso"
+            "#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn allocate_rule_needs_selector() {
+        let config: Config = toml::from_str(
+            r#"
+            repo_paths = []
+            [[repos."/r".allocate_rules]]
+            action = "drop"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            repo_paths = []
+            [[repos."/r".allocate_rules]]
+            action = "drop"
+            older_than_d = 30
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn embedded_git_patterns_need_valid_globs() {
+        let config: Config = toml::from_str(
+            r#"
+            repo_paths = []
+            [repos."/r"]
+            embedded_git_exclude = ["vendor/[a"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            repo_paths = []
+            [repos."/r"]
+            embedded_git_include = ["projects/*"]
+            embedded_git_exclude = ["vendor"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+    }
+}
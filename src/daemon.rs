@@ -2,12 +2,11 @@ use async_cron_scheduler::{cron, Job, Scheduler};
 use chrono::{prelude::*, Duration};
 use cron::Schedule;
 use glob::glob;
-use home::home_dir;
 use rand::Rng;
-use serde::Deserialize;
 use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use tao::event::Event;
 use tao::event_loop::{ControlFlow, EventLoopBuilder};
 use tokio::fs::File;
@@ -27,14 +26,19 @@ use tao::platform::macos::ActivationPolicy;
 use tao::platform::macos::EventLoopExtMacOS;
 
 use crate::commands::allocate::allocate;
+use crate::commands::coverage::is_coverage_healthy;
+use crate::commands::drill::drill;
 use crate::commands::maintain::maintain;
 use crate::commands::sync::sync;
-use crate::commands::LogTarget;
+use crate::commands::unchanged::{is_assume_unchanged_pending, recover_assume_unchanged};
+use crate::commands::watch::watch;
+use crate::commands::{lock_repo, log, LogTarget};
+use crate::config::{config_dir_path, read_config, Config};
 use crate::format::{
-    format_command_log_path, format_latest_submenu_item_text, format_latest_submenu_text,
-    format_maintain_status_text, format_next_item_text, format_repo_path_display,
-    format_repo_path_suffix, format_schedule_active_text, format_sync_status_text,
-    parse_command_log_path,
+    format_command_log_path, format_coverage_status_text, format_latest_submenu_item_text,
+    format_latest_submenu_text, format_maintain_status_text, format_next_item_text,
+    format_repo_path_display, format_repo_path_suffix, format_schedule_active_text,
+    format_sync_status_text, parse_command_log_path,
 };
 use crate::types::{CommandArgs, CommandLog, CommandMessage, CommandMessageType, CommandName};
 
@@ -68,18 +72,7 @@ pub(crate) async fn run_daemon() {
 
     let rng = &mut rand::thread_rng();
 
-    #[derive(Deserialize, Debug)]
-    struct Config {
-        repo_paths: Vec<String>,
-        maintain_timeout_m: Option<u64>,
-        maintain_schedule: Option<String>,
-        sync_schedule: Option<String>,
-        sync_unchanged_schedule: Option<String>,
-    }
-
-    let config_dir_path = home_dir()
-        .expect("unable to find home dir")
-        .join(".config/git-annex/archiver");
+    let config_dir_path = config_dir_path();
     fs::create_dir_all(config_dir_path.join("sync"))
         .expect("unable to create config sync directory");
     fs::create_dir_all(config_dir_path.join("maintain"))
@@ -87,22 +80,22 @@ pub(crate) async fn run_daemon() {
     fs::create_dir_all(config_dir_path.join("allocate"))
         .expect("unable to create config maintain directory");
 
-    let config: Config = toml::from_str(
-        &fs::read_to_string(config_dir_path.join("config")).expect("unable to read config"),
-    )
-    .expect("unable to parse config");
+    let config: Config = read_config(&config_dir_path).unwrap_or_else(|e| panic!("{}", e));
 
     let config_repo_paths: Vec<PathBuf> =
         config.repo_paths.iter().map(|s| PathBuf::from(s)).collect();
     let config_sync_schedule = config
         .sync_schedule
+        .clone()
         .unwrap_or(format!("0 {} * * * * *", rng.gen_range(0..59)));
     let config_sync_unchanged_schedule = config
         .sync_unchanged_schedule
+        .clone()
         .unwrap_or(format!("0 {} * 1,15 * * *", rng.gen_range(0..59)));
     let config_maintain_timeout_m = config.maintain_timeout_m.unwrap_or(120);
     let config_maintain_schedule = config
         .maintain_schedule
+        .clone()
         .unwrap_or(format!("0 {} 4 * * * *", rng.gen_range(0..59)));
 
     let repo_paths: Vec<PathBuf> = config_repo_paths;
@@ -206,6 +199,13 @@ pub(crate) async fn run_daemon() {
     .unwrap();
 
     let maintain_status_i = MenuItem::new(format_maintain_status_text(&false), false, None);
+    // kept for the tray icon, which other commands ending would otherwise reset
+    let mut coverage_is_healthy = is_coverage_healthy(&repo_paths);
+    let coverage_status_i = MenuItem::new(
+        format_coverage_status_text(&coverage_is_healthy),
+        false,
+        None,
+    );
     let maintain_latest_i = Submenu::new(
         format_latest_submenu_text(
             CommandName::Maintain,
@@ -276,6 +276,7 @@ pub(crate) async fn run_daemon() {
             &sync_latest_i,
             &PredefinedMenuItem::separator(),
             &maintain_status_i,
+            &coverage_status_i,
             &maintain_next_i,
             &maintain_latest_i,
             &PredefinedMenuItem::separator(),
@@ -316,9 +317,11 @@ pub(crate) async fn run_daemon() {
         },
         MaintainEnded {
             is_ok: bool,
+            is_healthy: bool,
         },
         AllocateStarted {
             command_dt: DateTime<Local>,
+            suffix: Option<String>,
         },
         AllocateEnded {
             is_ok: bool,
@@ -342,9 +345,35 @@ pub(crate) async fn run_daemon() {
     ) = mpsc::channel(1);
 
     let spawn_sync_config_dir_path = config_dir_path.clone();
+    let spawn_sync_config = config.clone();
+    let spawn_sync_repo_paths: Vec<PathBuf> = repo_paths.clone();
     let spawn_sync_event_loop_proxy: tao::event_loop::EventLoopProxy<CustomEvent> =
         event_loop.create_proxy();
     tokio::spawn(async move {
+        // files left with the assume-unchanged bit by a sync the daemon did not finish would be
+        // kept out of every commit, even by syncs including all files
+        let recover_repo_paths: Vec<&PathBuf> = spawn_sync_repo_paths
+            .iter()
+            .filter(|x| is_assume_unchanged_pending(x))
+            .collect();
+        if !recover_repo_paths.is_empty() {
+            if let Ok(mut logfile) = File::create(&format_command_log_path(
+                &spawn_sync_config_dir_path,
+                CommandName::Sync,
+                &Local::now(),
+                &Some(String::from("recover")),
+            ))
+            .await
+            {
+                let log_target = &mut LogTarget::File(&mut logfile);
+                for repo_path in recover_repo_paths {
+                    if let Some(_repo_lock) = lock_repo(repo_path, "recover", log_target).await {
+                        recover_assume_unchanged(repo_path, log_target).await;
+                    }
+                }
+            }
+        }
+
         let notify_progress = |progress| {
             spawn_sync_event_loop_proxy
                 .send_event(CustomEvent::CommandProgressNotified {
@@ -388,6 +417,8 @@ pub(crate) async fn run_daemon() {
                 let is_ok = sync(
                     &command_message.command_args.repo_paths,
                     command_message.command_args.includes_unchanged.unwrap(),
+                    false,
+                    &spawn_sync_config,
                     &mut LogTarget::File(&mut logfile),
                     notify_progress,
                 )
@@ -402,6 +433,7 @@ pub(crate) async fn run_daemon() {
     });
 
     let spawn_maintain_config_dir_path = config_dir_path.clone();
+    let spawn_maintain_config = config.clone();
     let spawn_maintain_event_loop_proxy: tao::event_loop::EventLoopProxy<CustomEvent> =
         event_loop.create_proxy();
     tokio::spawn(async move {
@@ -447,6 +479,7 @@ pub(crate) async fn run_daemon() {
                 let is_ok = maintain(
                     &command_message.command_args.repo_paths,
                     maintain_timeout_m,
+                    &spawn_maintain_config,
                     (
                         &mut LogTarget::File(&mut logfile),
                         &mut LogTarget::File(&mut logfile_sync),
@@ -455,8 +488,9 @@ pub(crate) async fn run_daemon() {
                 )
                 .await
                 .unwrap();
+                let is_healthy = is_coverage_healthy(&command_message.command_args.repo_paths);
                 spawn_maintain_event_loop_proxy
-                    .send_event(CustomEvent::MaintainEnded { is_ok })
+                    .send_event(CustomEvent::MaintainEnded { is_ok, is_healthy })
                     .ok();
                 prev_ended_dt = Some(Local::now());
             }
@@ -464,6 +498,7 @@ pub(crate) async fn run_daemon() {
     });
 
     let spawn_allocate_config_dir_path = config_dir_path.clone();
+    let spawn_allocate_config = config.clone();
     let spawn_allocate_event_loop_proxy: tao::event_loop::EventLoopProxy<CustomEvent> =
         event_loop.create_proxy();
     tokio::spawn(async move {
@@ -476,26 +511,29 @@ pub(crate) async fn run_daemon() {
                 .ok();
         };
 
-        let mut prev_command_dt: Option<DateTime<Local>> = None;
         while let Some(command_message) = allocate_command_rx.recv().await {
             let command_dt = command_message.command_dt;
 
             spawn_allocate_event_loop_proxy
-                .send_event(CustomEvent::AllocateStarted { command_dt })
+                .send_event(CustomEvent::AllocateStarted {
+                    command_dt,
+                    suffix: command_message.command_args.suffix.clone(),
+                })
                 .ok();
 
             let mut logfile = File::create(&format_command_log_path(
                 &spawn_allocate_config_dir_path,
                 CommandName::Allocate,
                 &command_dt,
-                &None,
+                &command_message.command_args.suffix,
             ))
             .await
             .expect("unable to create allocate log");
 
             let is_ok = allocate(
                 &command_message.command_args.repo_paths,
-                prev_command_dt,
+                command_message.command_args.file_paths.as_ref(),
+                &spawn_allocate_config,
                 &mut LogTarget::File(&mut logfile),
                 notify_progress,
             )
@@ -504,7 +542,6 @@ pub(crate) async fn run_daemon() {
             spawn_allocate_event_loop_proxy
                 .send_event(CustomEvent::AllocateEnded { is_ok })
                 .ok();
-            prev_command_dt = Some(command_dt);
         }
     });
 
@@ -520,12 +557,63 @@ pub(crate) async fn run_daemon() {
                     repo_paths: init_allocate_repo_paths.clone(),
                     includes_unchanged: None,
                     suffix: None,
+                    file_paths: None,
                 },
             })
             .await
             .unwrap();
     });
 
+    if config.allocate_watch.unwrap_or(false) {
+        let (watch_changed_tx, mut watch_changed_rx): (
+            Sender<Vec<PathBuf>>,
+            Receiver<Vec<PathBuf>>,
+        ) = mpsc::channel(1);
+        let watch_repo_paths: Vec<PathBuf> = repo_paths.clone();
+        let watch_debounce_s = config.allocate_watch_debounce_s.unwrap_or(3);
+        let watch_config_dir_path = config_dir_path.clone();
+        tokio::spawn(async move {
+            // Without a watcher, changes wait for the scheduled allocate
+            if let Err(e) = watch(&watch_repo_paths, watch_debounce_s, watch_changed_tx).await {
+                if let Ok(mut logfile) = File::create(&format_command_log_path(
+                    &watch_config_dir_path,
+                    CommandName::Allocate,
+                    &Local::now(),
+                    &Some(String::from("watch")),
+                ))
+                .await
+                {
+                    log(
+                        &format!("watch not ok ({}), allocating on schedule only", e),
+                        &mut LogTarget::File(&mut logfile),
+                    )
+                    .await;
+                }
+            }
+        });
+
+        let watch_allocate_command_tx = allocate_command_tx.clone();
+        let watch_allocate_repo_paths: Vec<PathBuf> = repo_paths.clone();
+        tokio::spawn(async move {
+            while let Some(file_paths) = watch_changed_rx.recv().await {
+                watch_allocate_command_tx
+                    .send(CommandMessage {
+                        message_type: CommandMessageType::StartByManual,
+                        command_dt: Local::now(),
+                        command_name: CommandName::Allocate,
+                        command_args: CommandArgs {
+                            repo_paths: watch_allocate_repo_paths.clone(),
+                            includes_unchanged: None,
+                            suffix: Some(String::from("watch")),
+                            file_paths: Some(file_paths),
+                        },
+                    })
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+
     let (mut scheduler, scheduler_service) = Scheduler::<Local>::launch(tokio::time::sleep);
 
     let scheduler_sync_job = Job::cron_schedule(sync_schedule.clone());
@@ -576,6 +664,7 @@ pub(crate) async fn run_daemon() {
                                 true => Some(String::from("*")),
                                 false => None,
                             },
+                            file_paths: None,
                         },
                     })
                     .await
@@ -620,6 +709,7 @@ pub(crate) async fn run_daemon() {
                             repo_paths: scheduler_maintain_repo_paths,
                             includes_unchanged: None,
                             suffix: None,
+                            file_paths: None,
                         },
                     })
                     .await
@@ -628,6 +718,44 @@ pub(crate) async fn run_daemon() {
         })
         .await;
 
+    if let Some(config_drill_schedule) = &config.drill_schedule {
+        fs::create_dir_all(config_dir_path.join("drill"))
+            .expect("unable to create config drill directory");
+        let drill_schedule = Schedule::from_str(config_drill_schedule)
+            .expect("unabled to parse drill schedule, cron format");
+        let scheduler_drill_config_dir_path = config_dir_path.clone();
+        let scheduler_drill_repo_paths: Vec<PathBuf> = repo_paths.clone();
+        let scheduler_drill_sample_ct = config.drill_sample_ct.unwrap_or(3);
+        // a drill still running when the next one is due makes it skip
+        let scheduler_drill_lock = Arc::new(tokio::sync::Mutex::new(()));
+        scheduler
+            .insert(Job::cron_schedule(drill_schedule), move |_id| {
+                let scheduler_drill_config_dir_path = scheduler_drill_config_dir_path.clone();
+                let scheduler_drill_repo_paths = scheduler_drill_repo_paths.clone();
+                let scheduler_drill_lock = scheduler_drill_lock.clone();
+                tokio::spawn(async move {
+                    let Ok(_drill_guard) = scheduler_drill_lock.try_lock() else {
+                        return;
+                    };
+                    let mut logfile = File::create(&format_command_log_path(
+                        &scheduler_drill_config_dir_path,
+                        CommandName::Drill,
+                        &Local::now(),
+                        &None,
+                    ))
+                    .await
+                    .expect("unable to create drill log");
+                    drill(
+                        &scheduler_drill_repo_paths,
+                        scheduler_drill_sample_ct,
+                        &mut LogTarget::File(&mut logfile),
+                    )
+                    .await;
+                });
+            })
+            .await;
+    }
+
     let event_loop_day_proxy: tao::event_loop::EventLoopProxy<CustomEvent> =
         event_loop.create_proxy();
     let scheduler_day_job = Job::cron("1 0 0 * * *").unwrap();
@@ -723,11 +851,11 @@ pub(crate) async fn run_daemon() {
                     Some(&sync_logs[0]),
                 ));
                 if maintain_all_i.is_enabled() {
-                    if is_ok_all {
-                        tray_icon.set_icon(Some(event_error_icon.clone())).unwrap();
+                    if is_ok_all && coverage_is_healthy {
+                        tray_icon.set_icon(Some(event_base_icon.clone())).unwrap();
                         tray_icon.set_icon_as_template(true);
                     } else {
-                        tray_icon.set_icon(Some(event_base_icon.clone())).unwrap();
+                        tray_icon.set_icon(Some(event_error_icon.clone())).unwrap();
                         tray_icon.set_icon_as_template(true);
                     }
                 }
@@ -755,6 +883,7 @@ pub(crate) async fn run_daemon() {
                                 repo_paths: event_repo_paths.clone(),
                                 includes_unchanged: None,
                                 suffix: None,
+                                file_paths: None,
                             },
                         })
                         .await
@@ -818,14 +947,20 @@ pub(crate) async fn run_daemon() {
                 }
                 maintain_latest_i.set_enabled(true);
             }
-            Event::UserEvent(CustomEvent::MaintainEnded { is_ok }) => {
+            Event::UserEvent(CustomEvent::MaintainEnded { is_ok, is_healthy }) => {
                 maintain_logs[0].is_ongoing = false;
                 maintain_logs[0].is_ok = Some(is_ok);
                 maintain_all_i.set_enabled(true);
+                coverage_is_healthy = is_healthy;
                 if sync_all_i.is_enabled() {
-                    tray_icon.set_icon(Some(event_base_icon.clone())).unwrap();
+                    if is_healthy {
+                        tray_icon.set_icon(Some(event_base_icon.clone())).unwrap();
+                    } else {
+                        tray_icon.set_icon(Some(event_error_icon.clone())).unwrap();
+                    }
                     tray_icon.set_icon_as_template(true);
                 }
+                coverage_status_i.set_text(format_coverage_status_text(&is_healthy));
 
                 maintain_latest_i.set_text(format_latest_submenu_text(
                     CommandName::Maintain,
@@ -840,7 +975,7 @@ pub(crate) async fn run_daemon() {
                     .set_text(format_latest_submenu_item_text(&maintain_logs[0]));
                 maintain_status_i.set_text(format_maintain_status_text(&is_ok));
             }
-            Event::UserEvent(CustomEvent::AllocateStarted { command_dt }) => {
+            Event::UserEvent(CustomEvent::AllocateStarted { command_dt, suffix }) => {
                 if sync_all_i.is_enabled() && maintain_all_i.is_enabled() {
                     tray_icon.set_icon(Some(event_active_icon.clone())).unwrap();
                     tray_icon.set_icon_as_template(true);
@@ -852,7 +987,7 @@ pub(crate) async fn run_daemon() {
                     CommandLog {
                         command_name: CommandName::Allocate,
                         command_dt,
-                        suffix: None,
+                        suffix,
                         progress: None,
                         is_ongoing: true,
                         is_ok: None,
@@ -896,7 +1031,11 @@ pub(crate) async fn run_daemon() {
                 allocate_logs[0].is_ok = Some(is_ok);
                 allocate_i.set_enabled(true);
                 if sync_all_i.is_enabled() && maintain_all_i.is_enabled() {
-                    tray_icon.set_icon(Some(event_base_icon.clone())).unwrap();
+                    if coverage_is_healthy {
+                        tray_icon.set_icon(Some(event_base_icon.clone())).unwrap();
+                    } else {
+                        tray_icon.set_icon(Some(event_error_icon.clone())).unwrap();
+                    }
                     tray_icon.set_icon_as_template(true);
                 }
 
@@ -938,6 +1077,7 @@ pub(crate) async fn run_daemon() {
                             Some(&allocate_logs[0]),
                         ));
                     }
+                    CommandName::Drill => (),
                 };
             }
             Event::UserEvent(CustomEvent::DayChanged) => {
@@ -1027,6 +1167,7 @@ pub(crate) async fn run_daemon() {
                                     repo_paths: vec![],
                                     includes_unchanged: None,
                                     suffix: None,
+                                    file_paths: None,
                                 },
                             })
                             .await
@@ -1058,6 +1199,7 @@ pub(crate) async fn run_daemon() {
                                     repo_paths: vec![],
                                     includes_unchanged: None,
                                     suffix: None,
+                                    file_paths: None,
                                 },
                             })
                             .await
@@ -1077,6 +1219,7 @@ pub(crate) async fn run_daemon() {
                                     repo_paths: event_repo_paths.clone(),
                                     includes_unchanged: Some(false),
                                     suffix: None,
+                                    file_paths: None,
                                 },
                             })
                             .await
@@ -1096,6 +1239,7 @@ pub(crate) async fn run_daemon() {
                                     repo_paths: event_repo_paths.clone(),
                                     includes_unchanged: None,
                                     suffix: None,
+                                    file_paths: None,
                                 },
                             })
                             .await
@@ -1115,6 +1259,7 @@ pub(crate) async fn run_daemon() {
                                     repo_paths: event_repo_paths.clone(),
                                     includes_unchanged: None,
                                     suffix: None,
+                                    file_paths: None,
                                 },
                             })
                             .await
@@ -1137,6 +1282,7 @@ pub(crate) async fn run_daemon() {
                                             repo_paths: vec![repo_path.to_owned()],
                                             includes_unchanged: Some(false),
                                             suffix: Some(format_repo_path_suffix(repo_path)),
+                                            file_paths: None,
                                         },
                                     })
                                     .await
@@ -31,6 +31,14 @@ fn format_dt(dt: &DateTime<Local>) -> String {
     );
 }
 
+pub fn format_timestamp_text(timestamp_s: u64) -> String {
+    format_dt(&Local.timestamp_opt(timestamp_s as i64, 0).unwrap())
+}
+
+pub fn format_size_text(size: u64) -> String {
+    format!("{:.1} MB", size as f64 / 1000.0 / 1000.0)
+}
+
 fn format_is_ok(is_ok: &Option<bool>) -> String {
     match is_ok {
         None => String::from(""),
@@ -106,6 +114,7 @@ pub fn format_latest_submenu_text(command_name: CommandName, log: Option<&Comman
                 CommandName::Sync => "Sync",
                 CommandName::Maintain => "Maintenance",
                 CommandName::Allocate => "Allocation",
+                CommandName::Drill => "Drill",
             }
         ),
         Some(log) => match log.is_ongoing {
@@ -115,6 +124,7 @@ pub fn format_latest_submenu_text(command_name: CommandName, log: Option<&Comman
                     CommandName::Sync => "Syncing",
                     CommandName::Maintain => "Running Maintenance",
                     CommandName::Allocate => "Allocating Files",
+                    CommandName::Drill => "Drilling Restores",
                 },
                 match &log.progress {
                     None => String::from(""),
@@ -127,6 +137,7 @@ pub fn format_latest_submenu_text(command_name: CommandName, log: Option<&Comman
                     CommandName::Sync => "Sync",
                     CommandName::Maintain => "Run",
                     CommandName::Allocate => "Allocation",
+                    CommandName::Drill => "Drill",
                 },
                 format_dt(&log.command_dt),
                 format_is_ok(&log.is_ok),
@@ -182,6 +193,13 @@ pub fn format_maintain_status_text(status_ok: &bool) -> String {
     };
 }
 
+pub fn format_coverage_status_text(is_healthy: &bool) -> String {
+    match is_healthy {
+        true => String::from("Enough Copies"),
+        false => String::from("Unhealthy, More Files Lacking Copies"),
+    }
+}
+
 pub fn format_schedule_active_text(
     command_name: CommandName,
     is_schedule_enabled: &bool,
@@ -212,11 +230,13 @@ pub fn format_command_log_path(
             CommandName::Sync => "sync",
             CommandName::Maintain => "maintain",
             CommandName::Allocate => "allocate",
+            CommandName::Drill => "drill",
         },
         match command_name {
             CommandName::Sync => "sync-",
             CommandName::Maintain => "maintain-",
             CommandName::Allocate => "allocate-",
+            CommandName::Drill => "drill-",
         },
         dt.format(LOG_DT_FORMAT).to_string(),
         match suffix {
@@ -250,6 +270,7 @@ pub fn parse_command_log_path(log_path: &PathBuf) -> CommandLog {
             "sync" => CommandName::Sync,
             "maintain" => CommandName::Maintain,
             "allocate" => CommandName::Allocate,
+            "drill" => CommandName::Drill,
             _ => CommandName::Sync,
         },
         command_dt: NaiveDateTime::parse_from_str(&log_segments[1..5].join("-"), LOG_DT_FORMAT)
@@ -1,15 +1,25 @@
 use clap::{Parser, Subcommand};
-use commands::LogTarget;
+use commands::{lock_repo, log, set_command_timeout, set_repo_lock_wait, LogTarget};
 use std::path::PathBuf;
-use tokio::io::{self};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::sync::mpsc;
 
-use crate::commands::maintain::maintain;
-use crate::commands::sync::sync;
+use crate::commands::allocate::allocate;
+use crate::commands::drill::drill;
+use crate::commands::maintain::{maintain, untrack_embedded_git};
+use crate::commands::status::status;
+use crate::commands::sync::{copies, sync};
+use crate::commands::unchanged::{
+    unchanged_add, unchanged_flags, unchanged_list, unchanged_remove,
+};
+use crate::commands::watch::watch;
+use crate::config::{config_dir_path, read_config, Config};
 
 #[cfg(not(target_os = "linux"))]
 use crate::daemon::run_daemon;
 
 pub mod commands;
+pub mod config;
 pub mod format;
 pub mod types;
 pub mod platform;
@@ -35,6 +45,10 @@ enum Commands {
 
         #[arg(long)]
         all: bool,
+
+        /// Commit and push even when more files were deleted or modified than configured
+        #[arg(long)]
+        allow_mass_change: bool,
     },
     /// Run maintenance tasks, checking a repository integrity, including previous versions
     Maintain {
@@ -44,6 +58,86 @@ enum Commands {
         #[arg(short, long, required = true)]
         timeout: u64,
     },
+    /// Restore a sample of content from each remote into a scratch clone, verifying it
+    Drill {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+
+        #[arg(short, long)]
+        sample_ct: Option<usize>,
+    },
+    /// Show what maintenance found in a repository, such as unused content
+    Status {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+    },
+    /// Unstage the git repositories embedded in a repository, as done during maintenance
+    Untrack {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Make copies of the git repositories embedded in a repository, or verify them
+    Copies {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Manage the files synced only along with unchanged files
+    Unchanged {
+        #[command(subcommand)]
+        command: UnchangedCommands,
+    },
+    /// Get or drop files according to their drop tag and the configured rules
+    Allocate {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+    },
+    /// Watch a repository, getting or dropping files as soon as their drop tag changes
+    Watch {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+
+        #[arg(short, long, default_value_t = 3)]
+        debounce: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UnchangedCommands {
+    /// Give files or folders the unchanged attribute, so they sync only along with unchanged files
+    Add {
+        #[arg(short, long, required = true)]
+        repo_path: String,
+
+        #[arg(num_args = 1.., required = true)]
+        paths: Vec<String>,
+    },
+    /// Take the unchanged attribute off files or folders
+    Remove {
+        #[arg(short, long, required = true)]
+        repo_path: String,
+
+        #[arg(num_args = 1.., required = true)]
+        paths: Vec<String>,
+    },
+    /// List the files with the unchanged attribute and when they were last synced
+    List {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+    },
+    /// List the files left hidden from git by an interrupted sync, or reset them
+    Flags {
+        #[arg(short, long, num_args = 1.., required = true)]
+        repo_paths: Vec<String>,
+
+        #[arg(long)]
+        reset: bool,
+    },
 }
 
 async fn setup_daemon() {
@@ -51,18 +145,42 @@ async fn setup_daemon() {
     run_daemon().await;
 }
 
+/// Config for a command, the defaults without a config file, exiting when it is not valid
+fn read_cli_config() -> Config {
+    let config_dir_path = config_dir_path();
+    if !config_dir_path.join("config").exists() {
+        return Config::default();
+    }
+    read_config(&config_dir_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    let config = read_config(&config_dir_path()).ok();
+    set_command_timeout(config.as_ref().and_then(|x| x.command_timeout_m));
+    set_repo_lock_wait(config.as_ref().and_then(|x| x.repo_lock_wait_m));
 
     match args.command {
         Some(Commands::Daemon) => {
             setup_daemon().await;
         }
-        Some(Commands::Sync { repo_paths, all }) => {
+        Some(Commands::Sync {
+            repo_paths,
+            all,
+            allow_mass_change,
+        }) => {
             sync(
-                &repo_paths.into_iter().map(|x| PathBuf::from(&x)).collect(),
+                &repo_paths
+                    .into_iter()
+                    .map(|x| PathBuf::from(&x))
+                    .collect::<Vec<PathBuf>>(),
                 all,
+                allow_mass_change,
+                &read_cli_config(),
                 &mut LogTarget::Stdout(&mut io::stdout()),
                 |_| {}
             )
@@ -76,6 +194,7 @@ async fn main() {
             maintain(
                 &repo_paths.into_iter().map(|x| PathBuf::from(&x)).collect(),
                 timeout,
+                &read_cli_config(),
                 (
                     &mut LogTarget::Stdout(&mut io::stdout()),
                     &mut LogTarget::Stdout(&mut io::stdout()),
@@ -85,6 +204,190 @@ async fn main() {
             .await
             .unwrap();
         }
+        Some(Commands::Drill {
+            repo_paths,
+            sample_ct,
+        }) => {
+            let config = read_cli_config();
+            let mut stdout = io::stdout();
+            let is_ok = drill(
+                &repo_paths
+                    .into_iter()
+                    .map(|x| PathBuf::from(&x))
+                    .collect::<Vec<PathBuf>>(),
+                sample_ct.or(config.drill_sample_ct).unwrap_or(3),
+                &mut LogTarget::Stdout(&mut stdout),
+            )
+            .await;
+            stdout.flush().await.unwrap();
+            if !is_ok {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Status { repo_paths }) => {
+            status(
+                &repo_paths.into_iter().map(|x| PathBuf::from(&x)).collect(),
+                &mut LogTarget::Stdout(&mut io::stdout()),
+            )
+            .await;
+        }
+        Some(Commands::Untrack {
+            repo_paths,
+            dry_run,
+        }) => {
+            let config = read_cli_config();
+            let mut stdout = io::stdout();
+            let mut is_ok = true;
+            for repo_path in repo_paths.into_iter().map(|x| PathBuf::from(&x)) {
+                let Some(_repo_lock) =
+                    lock_repo(&repo_path, "untrack", &mut LogTarget::Stdout(&mut stdout)).await
+                else {
+                    is_ok = false;
+                    continue;
+                };
+                is_ok &= untrack_embedded_git(
+                    &repo_path,
+                    &config.repo_config(&repo_path),
+                    dry_run,
+                    &mut LogTarget::Stdout(&mut stdout),
+                )
+                .await;
+            }
+            stdout.flush().await.ok();
+            if !is_ok {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Copies { repo_paths, verify }) => {
+            let mut stdout = io::stdout();
+            let is_ok = copies(
+                &repo_paths
+                    .into_iter()
+                    .map(|x| PathBuf::from(&x))
+                    .collect::<Vec<PathBuf>>(),
+                verify,
+                &read_cli_config(),
+                &mut LogTarget::Stdout(&mut stdout),
+            )
+            .await;
+            stdout.flush().await.ok();
+            if !is_ok {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Unchanged {
+            command: UnchangedCommands::Add { repo_path, paths },
+        }) => {
+            let mut stdout = io::stdout();
+            let is_ok = unchanged_add(
+                &PathBuf::from(&repo_path),
+                &paths,
+                &mut LogTarget::Stdout(&mut stdout),
+            )
+            .await;
+            stdout.flush().await.unwrap();
+            if !is_ok {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Unchanged {
+            command: UnchangedCommands::Remove { repo_path, paths },
+        }) => {
+            let mut stdout = io::stdout();
+            let is_ok = unchanged_remove(
+                &PathBuf::from(&repo_path),
+                &paths,
+                &mut LogTarget::Stdout(&mut stdout),
+            )
+            .await;
+            stdout.flush().await.unwrap();
+            if !is_ok {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Unchanged {
+            command: UnchangedCommands::List { repo_paths },
+        }) => {
+            unchanged_list(
+                &repo_paths
+                    .into_iter()
+                    .map(|x| PathBuf::from(&x))
+                    .collect::<Vec<PathBuf>>(),
+                &mut LogTarget::Stdout(&mut io::stdout()),
+            )
+            .await;
+        }
+        Some(Commands::Unchanged {
+            command: UnchangedCommands::Flags { repo_paths, reset },
+        }) => {
+            let mut stdout = io::stdout();
+            let is_ok = unchanged_flags(
+                &repo_paths
+                    .into_iter()
+                    .map(|x| PathBuf::from(&x))
+                    .collect::<Vec<PathBuf>>(),
+                reset,
+                &mut LogTarget::Stdout(&mut stdout),
+            )
+            .await;
+            stdout.flush().await.unwrap();
+            if !is_ok {
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Allocate { repo_paths }) => {
+            allocate(
+                &repo_paths.into_iter().map(|x| PathBuf::from(&x)).collect::<Vec<PathBuf>>(),
+                None,
+                &read_cli_config(),
+                &mut LogTarget::Stdout(&mut io::stdout()),
+                |_| {},
+            )
+            .await;
+        }
+        Some(Commands::Watch {
+            repo_paths,
+            debounce,
+        }) => {
+            let repo_paths: Vec<PathBuf> =
+                repo_paths.into_iter().map(|x| PathBuf::from(&x)).collect();
+            let config = read_cli_config();
+            let (changed_tx, mut changed_rx) = mpsc::channel(1);
+
+            let watch_repo_paths = repo_paths.clone();
+            let watch_handle =
+                tokio::spawn(async move { watch(&watch_repo_paths, debounce, changed_tx).await });
+            while let Some(file_paths) = changed_rx.recv().await {
+                allocate(
+                    &repo_paths,
+                    Some(&file_paths),
+                    &config,
+                    &mut LogTarget::Stdout(&mut io::stdout()),
+                    |_| {},
+                )
+                .await;
+            }
+
+            // Without a watcher, changes are only picked up by allocating every file
+            if let Ok(Err(e)) = watch_handle.await {
+                let mut stdout = io::stdout();
+                log(
+                    &format!("watch not ok ({}), allocating all files instead", e),
+                    &mut LogTarget::Stdout(&mut stdout),
+                )
+                .await;
+                allocate(
+                    &repo_paths,
+                    None,
+                    &config,
+                    &mut LogTarget::Stdout(&mut stdout),
+                    |_| {},
+                )
+                .await;
+                stdout.flush().await.unwrap();
+                std::process::exit(1);
+            }
+        }
         None => {
             setup_daemon().await;
         }
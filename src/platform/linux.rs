@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+
+use crate::commands::{log, LogTarget};
+
+const TAG_XATTR_NAME: &str = "user.xdg.tags";
+const TAG_XATTR_DROP_ITEM_VALUE: &str = "Dropped";
+
+fn get_file_tags(file_path: &PathBuf) -> Vec<String> {
+    let tag_xattr: Option<Vec<u8>> = xattr::get(file_path, TAG_XATTR_NAME).unwrap_or(None);
+
+    match tag_xattr {
+        Some(_xattr) => String::from_utf8_lossy(&_xattr)
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(String::from)
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn set_file_xattr_tags(file_path: &PathBuf, tags: &[String]) -> Result<(), std::io::Error> {
+    match tags.is_empty() {
+        true => xattr::remove(file_path, TAG_XATTR_NAME),
+        false => xattr::set(file_path, TAG_XATTR_NAME, tags.join(",").as_bytes()),
+    }
+}
+
+pub fn has_file_drop_attr(file_path: &PathBuf) -> bool {
+    get_file_tags(file_path).contains(&TAG_XATTR_DROP_ITEM_VALUE.to_string())
+}
+
+pub async fn set_file_drop_attr(file_path: &PathBuf, log_target: &mut LogTarget<'_>) {
+    let mut file_tags = get_file_tags(file_path);
+    if !file_tags.contains(&TAG_XATTR_DROP_ITEM_VALUE.to_string()) {
+        file_tags.push(TAG_XATTR_DROP_ITEM_VALUE.to_string());
+        let set_result = set_file_xattr_tags(file_path, &file_tags);
+        log(
+            &format!(
+                "set-drop {} {}",
+                file_path.display(),
+                match set_result {
+                    Ok(_) => String::from("ok"),
+                    Err(err) => format!("not ok ({})", err),
+                }
+            ),
+            log_target,
+        )
+        .await;
+    }
+}
+
+pub async fn unset_file_drop_attr(file_path: &PathBuf, log_target: &mut LogTarget<'_>) {
+    let file_tags = get_file_tags(file_path);
+    if file_tags.contains(&TAG_XATTR_DROP_ITEM_VALUE.to_string()) {
+        let set_result = set_file_xattr_tags(
+            file_path,
+            &file_tags
+                .into_iter()
+                .filter(|x| !x.eq(TAG_XATTR_DROP_ITEM_VALUE))
+                .collect::<Vec<String>>(),
+        );
+        log(
+            &format!(
+                "unset-drop {} {}",
+                file_path.display(),
+                match set_result {
+                    Ok(_) => String::from("ok"),
+                    Err(err) => format!("not ok ({})", err),
+                }
+            ),
+            log_target,
+        )
+        .await;
+    }
+}
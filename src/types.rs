@@ -5,7 +5,8 @@ use std::path::PathBuf;
 pub enum CommandName {
   Sync,
   Maintain,
-  Allocate
+  Allocate,
+  Drill
 }
 
 #[derive(PartialEq, Debug)]
@@ -20,6 +21,7 @@ pub struct CommandArgs {
   pub repo_paths: Vec<PathBuf>,
   pub includes_unchanged: Option<bool>,
   pub suffix: Option<String>,
+  pub file_paths: Option<Vec<PathBuf>>,
 }
 
 pub struct CommandMessage {